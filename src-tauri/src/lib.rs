@@ -1,6 +1,12 @@
-use tauri::{AppHandle, Manager, Result, Runtime, WebviewUrl, Window, Emitter};
-use tauri_plugin_sql::{Migration, MigrationKind};
+use tauri::{AppHandle, Manager, Result, Runtime, State, WebviewUrl, Window, Emitter, Listener};
+use tauri_plugin_sql::{DbInstances, DbPool, Migration, MigrationKind};
 use std::env;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::pin::Pin;
+use std::task::Poll;
+use tokio_util::sync::CancellationToken;
 // Removed unused vibrancy imports as they're commented out in the code
 use serde::{Deserialize, Serialize};
 use reqwest;
@@ -8,6 +14,7 @@ use tokio_stream::StreamExt;
 use std::path::Path;
 use uuid::Uuid;
 use std::time::Duration; // Import Duration for presigning
+use tracing::{debug, error, info, warn};
 
 // Core Graphics imports for screen capture
 use core_graphics;
@@ -20,6 +27,11 @@ use aws_sdk_s3::primitives::ByteStream;
 use aws_sdk_s3::Client as S3Client;
 use aws_sdk_s3::config::Region;
 use aws_sdk_s3::presigning::PresigningConfig; // Import PresigningConfig
+use aws_smithy_types::body::SdkBody;
+use aws_smithy_runtime_api::client::http::{HttpClient, HttpConnector, HttpConnectorFuture, HttpConnectorSettings, SharedHttpConnector};
+use aws_smithy_runtime_api::client::orchestrator::{HttpRequest, HttpResponse};
+use aws_smithy_runtime_api::client::result::ConnectorError;
+use aws_smithy_runtime_api::client::runtime_components::RuntimeComponents;
 use anyhow::{anyhow, Context}; // Import anyhow and Context
 
 #[macro_use]
@@ -31,10 +43,186 @@ use {
     cocoa::foundation::NSRect,
 };
 
+// --- Config ---
+
+// Settings read from the environment once at startup and managed as state, so commands
+// don't re-parse env vars on every call. The R2 client in R2State is built from `r2`.
+struct AppConfig {
+    openai_api_key: Option<String>,
+    openai_base_url: String,
+    mastra_base_url: String,
+    mastra_api_key: Option<String>,
+    // EYE_STORAGE=local keeps captures in the app data directory instead of uploading them,
+    // so the app works offline and without R2 credentials
+    local_storage: bool,
+    max_concurrent_uploads: usize,
+    capture_shortcut: String,
+    r2: R2Settings,
+    proxy: ProxySettings,
+}
+
+// R2 (or other S3-compatible) storage settings; unset or blank variables are None
+struct R2Settings {
+    account_id: Option<String>,
+    access_key_id: Option<String>,
+    secret_access_key: Option<String>,
+    bucket_name: Option<String>,
+    // S3_ENDPOINT_URL points uploads at any S3-compatible store (MinIO, Backblaze B2, ...)
+    // instead of the endpoint derived from R2_ACCOUNT_ID
+    endpoint_url: Option<String>,
+    // R2 ignores the region, so "auto" stays the default
+    region: String,
+}
+
+// Outbound proxy settings from HTTPS_PROXY / HTTP_PROXY / NO_PROXY (upper or lower case)
+#[derive(Debug, Clone, Default)]
+struct ProxySettings {
+    https_proxy: Option<String>,
+    http_proxy: Option<String>,
+    no_proxy: Option<String>,
+}
+
+impl ProxySettings {
+    fn is_configured(&self) -> bool {
+        self.https_proxy.is_some() || self.http_proxy.is_some()
+    }
+}
+
+impl AppConfig {
+    fn from_env() -> Self {
+        let non_empty = |name: &str| env::var(name).ok().map(|value| value.trim().to_string()).filter(|value| !value.is_empty());
+
+        let config = AppConfig {
+            openai_api_key: non_empty("OPENAI_API_KEY"),
+            openai_base_url: non_empty("OPENAI_BASE_URL")
+                .map(|url| url.trim_end_matches('/').to_string())
+                .unwrap_or_else(|| DEFAULT_OPENAI_BASE_URL.to_string()),
+            mastra_base_url: non_empty("MASTRA_BASE_URL")
+                .map(|url| url.trim_end_matches('/').to_string())
+                .unwrap_or_else(|| DEFAULT_MASTRA_BASE_URL.to_string()),
+            mastra_api_key: non_empty("MASTRA_API_KEY"),
+            local_storage: non_empty("EYE_STORAGE").map_or(false, |value| value.eq_ignore_ascii_case("local")),
+            max_concurrent_uploads: non_empty("EYE_MAX_CONCURRENT_UPLOADS")
+                .and_then(|value| value.parse::<usize>().ok())
+                .filter(|permits| *permits > 0)
+                .unwrap_or(DEFAULT_MAX_CONCURRENT_UPLOADS),
+            capture_shortcut: non_empty("EYE_CAPTURE_SHORTCUT").unwrap_or_else(|| DEFAULT_CAPTURE_SHORTCUT.to_string()),
+            r2: R2Settings {
+                account_id: non_empty("R2_ACCOUNT_ID"),
+                access_key_id: non_empty("R2_ACCESS_KEY_ID"),
+                secret_access_key: non_empty("R2_SECRET_ACCESS_KEY"),
+                bucket_name: non_empty("R2_BUCKET_NAME"),
+                endpoint_url: non_empty("S3_ENDPOINT_URL").map(|url| url.trim_end_matches('/').to_string()),
+                region: non_empty("S3_REGION").unwrap_or_else(|| "auto".to_string()),
+            },
+            proxy: ProxySettings {
+                https_proxy: non_empty("HTTPS_PROXY").or_else(|| non_empty("https_proxy")),
+                http_proxy: non_empty("HTTP_PROXY").or_else(|| non_empty("http_proxy")),
+                no_proxy: non_empty("NO_PROXY").or_else(|| non_empty("no_proxy")),
+            },
+        };
+
+        // Surface missing keys at startup rather than on the first chat
+        if config.openai_api_key.is_none() {
+            warn!("OPENAI_API_KEY is not set; OpenAI chat is disabled");
+        }
+        config
+    }
+
+    fn openai_api_key(&self) -> std::result::Result<String, AppError> {
+        self.openai_api_key
+            .clone()
+            .ok_or_else(|| AppError::MissingEnvVar("OPENAI_API_KEY is not set. Add it to .env".to_string()))
+    }
+
+    fn openai_chat_completions_url(&self) -> String {
+        format!("{}/chat/completions", self.openai_base_url)
+    }
+
+    // Attach `Authorization: Bearer $MASTRA_API_KEY` when the key is set; without it requests go out as before
+    fn with_mastra_auth(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.mastra_api_key {
+            Some(key) => request.bearer_auth(key),
+            None => request,
+        }
+    }
+}
+
+// --- /Config ---
+
+// Build and environment details for the about screen and feature gating
+#[derive(Serialize)]
+struct AppInfo {
+    version: &'static str,
+    os: &'static str,
+    os_version: String,
+    r2_configured: bool,
+    mastra_base_url: String,
+}
+
+// "dark" or "light"; anything newer than the two maps to light
+fn theme_name(theme: tauri::Theme) -> &'static str {
+    match theme {
+        tauri::Theme::Dark => "dark",
+        _ => "light",
+    }
+}
+
+// The OS theme as the calling window sees it, so the transparent windows can match it
+#[tauri::command]
+fn get_system_theme(window: Window) -> std::result::Result<String, String> {
+    window
+        .theme()
+        .map(|theme| theme_name(theme).to_string())
+        .map_err(|e| format!("Failed to read system theme: {}", e))
+}
+
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 #[tauri::command]
-fn greet(name: &str) -> String {
-    format!("Hello, {}! You've been greeted from Rust!", name)
+fn app_info(r2: State<'_, R2State>, config: State<'_, AppConfig>) -> AppInfo {
+    AppInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        os: tauri_plugin_os::platform(),
+        os_version: tauri_plugin_os::version().to_string(),
+        r2_configured: r2.0.is_some(),
+        mastra_base_url: config.mastra_base_url.clone(),
+    }
+}
+
+// Errors returned by chat, R2 and capture commands. Serialized as `{ kind, message }`
+// so the frontend can switch on `error.kind` instead of matching message text.
+#[derive(Debug, thiserror::Error, Serialize)]
+#[serde(tag = "kind", content = "message", rename_all = "camelCase")]
+enum AppError {
+    #[error("{0}")]
+    MissingEnvVar(String),
+    #[error("{0}")]
+    InvalidInput(String),
+    #[error("{0}")]
+    Network(String),
+    // The remote service answered with an error status or an unreadable body
+    #[error("{0}")]
+    Api(String),
+    #[error("{0}")]
+    R2Upload(String),
+    #[error("{0}")]
+    CaptureFailed(String),
+    #[error("{0}")]
+    PermissionDenied(String),
+    #[error("{0}")]
+    OcrFailed(String),
+    #[error("{0}")]
+    Timeout(String),
+    #[error("{0}")]
+    Cancelled(String),
+    // The R2 object (or other resource) no longer exists
+    #[error("{0}")]
+    NotFound(String),
+    // The local notes database could not be opened or queried
+    #[error("{0}")]
+    Database(String),
+    #[error("{0}")]
+    Internal(String),
 }
 
 // Define a struct for the image URL content part
@@ -68,6 +256,9 @@ struct ChatMessage {
     content: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     image_url: Option<String>,
+    // R2 key of the attached image, stored instead of the expiring URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    image_key: Option<String>,
 }
 
 // Define a struct to deserialize the streaming chunk from Mastra
@@ -81,8 +272,167 @@ struct MastraStreamChunk {
     finish_reason: Option<String>,
 }
 
+// Cancellation tokens for in-flight Mastra streams, keyed by the request id the frontend generates
+#[derive(Default)]
+struct ChatStreams(Arc<Mutex<HashMap<String, CancellationToken>>>);
+
+// Cancellation tokens for in-flight R2 uploads, keyed by `{object key}#{upload id}`. Deduped
+// uploads of identical bytes share an object key, so each call needs its own entry.
+#[derive(Default)]
+struct UploadCancellations(Arc<Mutex<HashMap<String, CancellationToken>>>);
+
+fn upload_registration_id(key: &str) -> String {
+    format!("{}#{}", key, Uuid::new_v4())
+}
+
+// The object key a registration id was made from
+fn upload_registration_key(id: &str) -> &str {
+    id.rsplit_once('#').map_or(id, |(key, _)| key)
+}
+
+// Caps how many R2 uploads run at once so a burst of captures queues instead of
+// contending for bandwidth. Size comes from EYE_MAX_CONCURRENT_UPLOADS.
+struct UploadLimiter(Arc<tokio::sync::Semaphore>);
+
+const DEFAULT_MAX_CONCURRENT_UPLOADS: usize = 2;
+
+impl UploadLimiter {
+    fn new(permits: usize) -> Self {
+        UploadLimiter(Arc::new(tokio::sync::Semaphore::new(permits)))
+    }
+}
+
+// Removes a token from its registry however the owning command exits
+struct TokenRegistration {
+    registry: Arc<Mutex<HashMap<String, CancellationToken>>>,
+    id: String,
+}
+
+impl Drop for TokenRegistration {
+    fn drop(&mut self) {
+        if let Ok(mut registry) = self.registry.lock() {
+            registry.remove(&self.id);
+        }
+    }
+}
+
+// --- Shutdown ---
+
+// How long exit waits for cancelled streams and uploads to unwind
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+static SHUTTING_DOWN: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+// Signal every token in a registry, returning how many were registered
+fn cancel_all_tokens(registry: &Mutex<HashMap<String, CancellationToken>>) -> usize {
+    match registry.lock() {
+        Ok(tokens) => {
+            tokens.values().for_each(CancellationToken::cancel);
+            tokens.len()
+        }
+        Err(e) => {
+            warn!("Failed to lock token registry: {}", e);
+            0
+        }
+    }
+}
+
+fn registry_len(registry: &Mutex<HashMap<String, CancellationToken>>) -> usize {
+    registry.lock().map_or(0, |tokens| tokens.len())
+}
+
+// Cancel all chat streams (chat_mastra and chat_stream both register in ChatStreams) and
+// uploads, returning how many were running
+fn cancel_in_flight_work<R: Runtime>(app: &AppHandle<R>) -> usize {
+    cancel_all_tokens(&app.state::<ChatStreams>().0) + cancel_all_tokens(&app.state::<UploadCancellations>().0)
+}
+
+// Panic button: stop every chat stream and upload at once, then tell all windows
+// via `all_cancelled` (carrying how many operations were signalled)
+#[tauri::command]
+fn cancel_all<R: Runtime>(app: AppHandle<R>) -> std::result::Result<(), String> {
+    let cancelled = cancel_in_flight_work(&app);
+    info!("Cancelled {} in-flight operations", cancelled);
+    app.emit("all_cancelled", cancelled)
+        .map_err(|e| format!("Failed to emit all_cancelled: {}", e))
+}
+
+// Registrations drop as their commands return, so empty registries mean everything has unwound
+async fn wait_for_in_flight_work<R: Runtime>(app: &AppHandle<R>, grace_period: Duration) {
+    let deadline = tokio::time::Instant::now() + grace_period;
+    while tokio::time::Instant::now() < deadline {
+        let remaining = registry_len(&app.state::<ChatStreams>().0) + registry_len(&app.state::<UploadCancellations>().0);
+        if remaining == 0 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    warn!("Exiting with work still in flight after {:?}", grace_period);
+}
+
+// --- /Shutdown ---
+
+// Default popup geometry, used for any dimension the frontend doesn't supply
+const POPUP_DEFAULT_SIZE: (f64, f64) = (400.0, 300.0);
+const POPUP_DEFAULT_POSITION: (f64, f64) = (100.0, 100.0);
+
+// Metadata from the 'f' prefix that opens a Mastra stream, re-emitted as `chat_stream_start`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct MastraStreamStart {
+    message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<String>,
+}
+
+// Token usage reported in Mastra's end markers
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct MastraUsage {
+    prompt_tokens: Option<u64>,
+    completion_tokens: Option<u64>,
+}
+
+// Payload of the 'e'/'d' end markers, re-emitted as `chat_stream_complete`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct MastraStreamFinish {
+    finish_reason: Option<String>,
+    usage: Option<MastraUsage>,
+    // Set when finishReason is "length", i.e. the response was cut off
+    #[serde(default)]
+    truncated: bool,
+}
+
+// Payload of the '9' prefix (a completed tool call), re-emitted as `chat_tool_call`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct MastraToolCall {
+    tool_call_id: String,
+    tool_name: String,
+    #[serde(default)]
+    args: serde_json::Value,
+}
+
+// Payload of the 'a' prefix (a tool's result), re-emitted as `chat_tool_result`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct MastraToolResult {
+    tool_call_id: String,
+    #[serde(default)]
+    result: serde_json::Value,
+}
+
 #[tauri::command]
-async fn open_popup_window<R: Runtime>(app: AppHandle<R>) -> Result<()> {
+async fn open_popup_window<R: Runtime>(
+    app: AppHandle<R>,
+    width: Option<f64>,
+    height: Option<f64>,
+    x: Option<f64>,
+    y: Option<f64>,
+) -> Result<()> {
     // Check if the window already exists
     if let Some(window) = app.get_webview_window("popup") {
         // If it exists, bring it to the front
@@ -91,8 +441,8 @@ async fn open_popup_window<R: Runtime>(app: AppHandle<R>) -> Result<()> {
         // If it doesn't exist, create it
         let builder = tauri::WebviewWindowBuilder::new(&app, "popup", WebviewUrl::App("popup.html".into()))
             .title("Popup Window")
-            .inner_size(400.0, 300.0)
-            .position(100.0, 100.0)
+            .inner_size(width.unwrap_or(POPUP_DEFAULT_SIZE.0), height.unwrap_or(POPUP_DEFAULT_SIZE.1))
+            .position(x.unwrap_or(POPUP_DEFAULT_POSITION.0), y.unwrap_or(POPUP_DEFAULT_POSITION.1))
             .transparent(true) 
             .decorations(false) // No window decorations (title bar, etc.)
             .resizable(true)
@@ -103,7 +453,7 @@ async fn open_popup_window<R: Runtime>(app: AppHandle<R>) -> Result<()> {
         // Create the window (prefix with underscore to indicate intentional unused variable)
         let _window = builder.build()?;
         
-        // Vibrancy effects are disabled as they're for transparent windows
+        // Vibrancy is off by default; the frontend can turn it on with set_popup_vibrancy
     }
     Ok(())
 }
@@ -116,691 +466,4501 @@ async fn close_popup_window<R: Runtime>(app: AppHandle<R>) -> Result<()> {
     Ok(())
 }
 
+// Close the popup if it's open, otherwise open it with the default geometry. Returns true when now open.
 #[tauri::command]
-async fn chat(prompt: String, messages_history: Vec<ChatMessage>) -> std::result::Result<String, String> {
-    use openai_rust::{Client, chat::ChatArguments};
-    // Role and Message are already used below, no need to import again here
+async fn toggle_popup_window<R: Runtime>(app: AppHandle<R>) -> Result<bool> {
+    if let Some(window) = app.get_webview_window("popup") {
+        window.close()?;
+        return Ok(false);
+    }
+    open_popup_window(app, None, None, None, None).await?;
+    Ok(true)
+}
 
-    dotenvy::dotenv().map_err(|e| format!("Failed to load .env file: {}", e))?;
-    let key = env::var("OPENAI_API_KEY").map_err(|e| format!("Failed to get OPENAI_API_KEY: {}", e))?;
-    let client = Client::new(&key);
+// Events that count as popup activity: streamed replies, plus `popup_activity`, which the
+// frontend emits on user input
+const POPUP_ACTIVITY_EVENTS: &[&str] = &["chat_chunk", "popup_activity"];
 
-    // Map the incoming Vec<ChatMessage> to Vec<openai_rust::chat::Message>
-    let mut history: Vec<openai_rust::chat::Message> = messages_history
-        .into_iter()
-        .map(|msg| openai_rust::chat::Message {
-            role: msg.role,
-            content: msg.content,
+// The popup's idle timer while auto-close is enabled
+#[derive(Default)]
+struct PopupAutoClose(Mutex<Option<PopupIdleTimer>>);
+
+struct PopupIdleTimer {
+    cancel: CancellationToken,
+    listeners: Vec<tauri::EventId>,
+}
+
+// Cancel the idle timer and drop its activity listeners. Returns false if none was running.
+fn stop_popup_idle_timer<R: Runtime>(app: &AppHandle<R>) -> bool {
+    let timer = match app.state::<PopupAutoClose>().0.lock() {
+        Ok(mut timer) => timer.take(),
+        Err(e) => {
+            warn!("Failed to lock popup auto-close state: {}", e);
+            None
+        }
+    };
+    let Some(timer) = timer else {
+        return false;
+    };
+    timer.cancel.cancel();
+    for id in timer.listeners {
+        app.unlisten(id);
+    }
+    true
+}
+
+// Close the popup after `idle_secs` without activity. Each activity event restarts the wait;
+// calling this again replaces the running timer.
+#[tauri::command]
+fn enable_popup_auto_close<R: Runtime>(app: AppHandle<R>, idle_secs: u64) -> std::result::Result<(), String> {
+    if idle_secs == 0 {
+        return Err("idle_secs must be at least 1".to_string());
+    }
+    stop_popup_idle_timer(&app);
+
+    // notify_one keeps a permit when the timer isn't waiting, so activity is never missed
+    let activity = Arc::new(tokio::sync::Notify::new());
+    let listeners = POPUP_ACTIVITY_EVENTS
+        .iter()
+        .map(|event| {
+            let activity = activity.clone();
+            app.listen_any(*event, move |_| activity.notify_one())
         })
         .collect();
+    let cancel = CancellationToken::new();
+    *app.state::<PopupAutoClose>()
+        .0
+        .lock()
+        .map_err(|e| format!("Failed to lock popup auto-close state: {}", e))? =
+        Some(PopupIdleTimer { cancel: cancel.clone(), listeners });
 
-    // Add the new user prompt to the history
-    history.push(openai_rust::chat::Message {
-        role: "user".to_string(),
-        content: prompt, // Use the separate prompt variable
+    let idle = Duration::from_secs(idle_secs);
+    tauri::async_runtime::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                _ = activity.notified() => continue,
+                _ = tokio::time::sleep(idle) => break,
+            }
+        }
+        // Lost a race with disable or a replacement timer
+        if cancel.is_cancelled() {
+            return;
+        }
+        debug!("Popup idle for {:?}, closing it", idle);
+        stop_popup_idle_timer(&app);
+        if let Err(e) = close_popup_window(app).await {
+            warn!("Failed to auto-close popup: {}", e);
+        }
     });
+    Ok(())
+}
 
-    // Use the full history in the arguments
-    let args = ChatArguments::new(
-        "gpt-4o",
-        history // Pass the combined history
-    );
+// Returns false if auto-close wasn't enabled
+#[tauri::command]
+fn disable_popup_auto_close<R: Runtime>(app: AppHandle<R>) -> bool {
+    stop_popup_idle_timer(&app)
+}
 
-    // Use tokio runtime for the async call
-    // map_err ensures the error type matches the function's String error type
-    let result = tokio::task::spawn(async move {
-        client.create_chat(args).await
-    }).await.map_err(|e| format!("Task join error: {}", e))?; // Handle potential join error
-
-    // The rest of the match statement is now correct because the error types align
-    match result {
-        Ok(res) => {
-            // Check if choices are available and get the first one
-            if let Some(choice) = res.choices.get(0) {
-                // Access content directly assuming it's String, not Option<String>
-                // Check if message and content exist (though content error suggests it's not Option)
-                // A simple access like choice.message.content.clone() might work if message is guaranteed
-                // Let's keep it simpler for now based on the error.
-                 Ok(choice.message.content.clone()) // Directly clone the content String
-            } else {
-                Err("OpenAI response did not contain any choices.".to_string())
-            }
+// Blur what's behind the transparent popup: vibrancy on macOS, acrylic-style blur on Windows
+#[tauri::command]
+async fn set_popup_vibrancy<R: Runtime>(app: AppHandle<R>, enabled: bool) -> std::result::Result<(), String> {
+    let window = app
+        .get_webview_window("popup")
+        .ok_or_else(|| "Popup window is not open".to_string())?;
+
+    #[cfg(target_os = "macos")]
+    {
+        use window_vibrancy::{apply_vibrancy, clear_vibrancy, NSVisualEffectMaterial};
+        if enabled {
+            apply_vibrancy(&window, NSVisualEffectMaterial::HudWindow, None, None)
+                .map_err(|e| format!("Failed to apply vibrancy: {}", e))?;
+        } else {
+            clear_vibrancy(&window).map_err(|e| format!("Failed to clear vibrancy: {}", e))?;
+        }
+        return Ok(());
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        use window_vibrancy::{apply_blur, clear_blur};
+        if enabled {
+            apply_blur(&window, Some((18, 18, 18, 125))).map_err(|e| format!("Failed to apply blur: {}", e))?;
+        } else {
+            clear_blur(&window).map_err(|e| format!("Failed to clear blur: {}", e))?;
         }
-        Err(e) => Err(format!("OpenAI API error: {}", e)),
+        return Ok(());
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    {
+        let _ = (window, enabled);
+        Err("Window vibrancy is not supported on this platform".to_string())
     }
 }
 
-// --- MODIFIED COMMAND ---
+// Keep windows from fading out entirely
+const MIN_WINDOW_OPACITY: f64 = 0.1;
+
+// Fade an Eye window (e.g. "popup") at runtime. macOS only: NSWindow's alphaValue has
+// no portable equivalent in Tauri, so other platforms get an error.
 #[tauri::command]
-async fn chat_mastra<R: Runtime>(
-    prompt: String,
-    messages_history: Vec<ChatMessage>,
-    image_url: Option<String>,
-    app: AppHandle<R>,
-) -> std::result::Result<(), String> {
-    let mastra_endpoint = "http://localhost:4111/api/agents/weatherAgent/stream";
-    // Create a client with optimized timeout and pool settings
-    let client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(30))  // Set a reasonable timeout 
-        .pool_max_idle_per_host(10)        // Keep connections alive for reuse
-        .build()
-        .map_err(|e| format!("Failed to build HTTP client: {}", e))?;
+async fn set_window_opacity<R: Runtime>(app: AppHandle<R>, label: String, opacity: f64) -> std::result::Result<(), String> {
+    let window = app
+        .get_webview_window(&label)
+        .ok_or_else(|| format!("Window '{}' is not open", label))?;
+    let opacity = if opacity.is_finite() { opacity.clamp(MIN_WINDOW_OPACITY, 1.0) } else { 1.0 };
 
-    // Start constructing the messages payload for Mastra
-    let mut final_messages_payload: Vec<serde_json::Value> = Vec::new();
+    #[cfg(target_os = "macos")]
+    {
+        // AppKit must be touched on the main thread
+        let target = window.clone();
+        return window
+            .run_on_main_thread(move || match target.ns_window() {
+                Ok(ns_window) => unsafe {
+                    let _: () = msg_send![ns_window as *mut Object, setAlphaValue: opacity];
+                },
+                Err(e) => warn!("Failed to get NSWindow for {}: {}", target.label(), e),
+            })
+            .map_err(|e| format!("Failed to set window opacity: {}", e));
+    }
 
-    // Process history messages (assuming simple text content for now)
-    for msg in messages_history {
-        final_messages_payload.push(serde_json::json!({
-            "role": msg.role,
-            "content": [{ "type": "text", "text": msg.content }]
-        }));
+    #[cfg(not(target_os = "macos"))]
+    {
+        let _ = (window, opacity);
+        Err("Window opacity is not supported on this platform".to_string())
     }
+}
 
-    // Construct the current user message payload
-    let mut current_user_content: Vec<ContentPart> = Vec::new();
+#[tauri::command]
+async fn set_popup_always_on_top<R: Runtime>(app: AppHandle<R>, enabled: bool) -> Result<()> {
+    if let Some(window) = app.get_webview_window("popup") {
+        window.set_always_on_top(enabled)?;
+    }
+    Ok(())
+}
 
-    // Add text part if prompt is not empty
-    if !prompt.trim().is_empty() {
-        current_user_content.push(ContentPart::Text(TextContent {
-            type_field: "text".to_string(),
-            text: prompt.trim().to_string(),
-        }));
+// Models the frontend may request from `chat`, so a typo fails fast instead of as an opaque API error
+const DEFAULT_OPENAI_MODEL: &str = "gpt-4o";
+const ALLOWED_OPENAI_MODELS: &[&str] = &["gpt-4o", "gpt-4o-mini", "gpt-4-turbo"];
+
+// OpenAI API root, overridable via OPENAI_BASE_URL for gateways and compatible servers
+const DEFAULT_OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+
+// Subset of the chat completions response we read
+#[derive(Deserialize, Debug)]
+struct OpenAiChatCompletion {
+    // The exact snapshot that served the request, e.g. gpt-4o-2024-08-06
+    #[serde(default)]
+    model: Option<String>,
+    choices: Vec<OpenAiChoice>,
+    #[serde(default)]
+    usage: Option<OpenAiUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiUsage {
+    prompt_tokens: u32,
+    completion_tokens: u32,
+}
+
+// What `chat` returns: the reply plus what it cost, for tracking usage
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ChatResponse {
+    content: String,
+    model: String,
+    prompt_tokens: u32,
+    completion_tokens: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiChoice {
+    message: OpenAiResponseMessage,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiResponseMessage {
+    content: Option<String>,
+}
+
+// One `data:` event of a streamed chat completion
+#[derive(Deserialize, Debug)]
+struct OpenAiStreamChunk {
+    choices: Vec<OpenAiStreamChoice>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiStreamChoice {
+    delta: OpenAiResponseMessage,
+}
+
+fn openai_http_client(proxy: &ProxySettings) -> std::result::Result<reqwest::Client, String> {
+    with_proxy(proxy, reqwest::Client::builder())?
+        .build()
+        .map_err(|e| format!("Failed to build HTTP client: {}", e))
+}
+
+// Resolve the model, defaulting to gpt-4o when none is given, and check the temperature
+fn resolve_openai_model(model: Option<String>, temperature: Option<f32>) -> std::result::Result<String, AppError> {
+    let model = model.unwrap_or_else(|| DEFAULT_OPENAI_MODEL.to_string());
+    if !ALLOWED_OPENAI_MODELS.contains(&model.as_str()) {
+        return Err(AppError::InvalidInput(format!(
+            "Unsupported OpenAI model '{}'. Expected one of: {}",
+            model,
+            ALLOWED_OPENAI_MODELS.join(", ")
+        )));
     }
 
-    // Add image URL part if provided (now expects a pre-signed URL)
-    if let Some(url) = image_url {
-         println!("Image URL received in chat_mastra: {}", url); // Debugging
-        // Basic validation for URL format might still be useful, but R2 presigned URLs are complex
-        if url.starts_with("https://") {
-            current_user_content.push(ContentPart::ImageUrl(ImageUrlContent {
-                type_field: "image".to_string(),
-                image: url,
-            }));
-        } else {
-            eprintln!("Warning: Provided image_url does not look like a secure pre-signed URL: {}", url);
-            // Decide if you want to proceed or error out if the URL is not HTTPS
+    // OpenAI accepts sampling temperatures between 0 and 2
+    if let Some(t) = temperature {
+        if !(0.0..=2.0).contains(&t) {
+            return Err(AppError::InvalidInput(format!("Temperature must be between 0 and 2, got {}", t)));
         }
     }
+    Ok(model)
+}
 
-    // Add the fully constructed user message to the payload
-    if !current_user_content.is_empty() {
-        final_messages_payload.push(serde_json::json!({
-            "role": "user",
-            "content": current_user_content
-        }));
-    } else {
-        // Allow sending empty text prompt if an image URL *is* provided
-        if final_messages_payload.iter().any(|m| m["role"] == "user" && m["content"].as_array().map_or(false, |c| c.iter().any(|p| p["type"] == "image_url"))) {
-             println!("Sending message with only image.");
-        } else {
-             return Err("Cannot send an empty message without an image.".to_string());
-        }
+// Emit a chat event when there's a window to receive it; without one this does nothing
+fn emit_to_chat_window<R: Runtime, S: Serialize + Clone>(
+    window: Option<&tauri::WebviewWindow<R>>,
+    event: &str,
+    payload: S,
+) -> std::result::Result<(), String> {
+    match window {
+        Some(window) => window.emit(event, payload).map_err(|e| format!("Failed to emit {}: {}", event, e)),
+        None => Ok(()),
     }
+}
 
-    // Prepare request body according to Mastra stream API
-    let request_body = serde_json::json!({
-        "messages": final_messages_payload,
-    });
+// The window chat events go to: the popup if it's open, otherwise the drag chat
+fn chat_window<R: Runtime>(app: &AppHandle<R>) -> std::result::Result<tauri::WebviewWindow<R>, String> {
+    app.get_webview_window("popup")
+        .or_else(|| app.get_webview_window("drag-chat"))
+        .ok_or_else(|| "Neither popup nor drag-chat window found".to_string())
+}
 
-    println!("Sending request to Mastra stream API. Payload:");
-    println!("{}", serde_json::to_string_pretty(&request_body).unwrap_or_default());
+#[tauri::command]
+#[tracing::instrument(skip_all, fields(model = ?model))]
+async fn chat<R: Runtime>(
+    app: AppHandle<R>,
+    config: State<'_, AppConfig>,
+    prompt: String,
+    messages_history: Vec<ChatMessage>,
+    model: Option<String>,
+    system: Option<String>,
+    temperature: Option<f32>,
+    image_url: Option<String>,
+    conversation_id: Option<String>,
+) -> std::result::Result<ChatResponse, AppError> {
+    let model = resolve_openai_model(model, temperature)?;
+    let key = config.openai_api_key()?;
 
-    // Try to get either the popup window or the drag-chat window
-    let window = app.get_webview_window("popup")
-        .or_else(|| app.get_webview_window("drag-chat"))
-        .ok_or_else(|| "Neither popup nor drag-chat window found".to_string())?;
+    // An explicit system prompt wins over the one stored on the conversation
+    let system = match system.filter(|s| !s.trim().is_empty()) {
+        Some(system) => Some(system),
+        None => match &conversation_id {
+            Some(id) => conversation_system_prompt(&app, id).await.map_err(AppError::Database)?,
+            None => None,
+        },
+    };
+
+    // The request body is built by hand because vision input needs multi-part content
+    let mut messages: Vec<serde_json::Value> = Vec::new();
+
+    // Start with the system prompt, if any, so it frames the whole conversation
+    if let Some(system) = system.filter(|s| !s.trim().is_empty()) {
+        messages.push(serde_json::json!({ "role": "system", "content": system }));
+    }
+
+    // Carry over the history as plain text messages
+    for msg in messages_history {
+        messages.push(serde_json::json!({ "role": msg.role, "content": msg.content }));
+    }
+
+    // Add the new user prompt, with the image as a second content part when provided
+    match image_url {
+        Some(url) => {
+            if !(url.starts_with("https://") || url.starts_with("data:image/")) {
+                return Err(AppError::InvalidInput(format!("Image URL must be https:// or a data:image/ URL, got: {}", url)));
+            }
+            messages.push(serde_json::json!({
+                "role": "user",
+                "content": [
+                    { "type": "text", "text": prompt },
+                    { "type": "image_url", "image_url": { "url": url } }
+                ]
+            }));
+        }
+        None => messages.push(serde_json::json!({ "role": "user", "content": prompt })),
+    }
 
-    // Execute the request and process the stream
-    let res = client.post(mastra_endpoint)
+    let mut request_body = serde_json::json!({
+        "model": model,
+        "messages": messages,
+    });
+    if let Some(t) = temperature {
+        request_body["temperature"] = serde_json::json!(t);
+    }
+
+    let res = openai_http_client(&config.proxy)
+        .map_err(AppError::InvalidInput)?
+        .post(config.openai_chat_completions_url())
+        .bearer_auth(&key)
         .json(&request_body)
         .send()
         .await
-        .map_err(|e| format!("Failed to send request to Mastra server: {}", e))?;
+        .map_err(|e| AppError::Network(format!("Failed to send request to OpenAI: {}", e)))?;
 
     let status = res.status();
-    println!("Received response from Mastra. Status: {}", status);
-
     if !status.is_success() {
         let error_text = res.text().await.unwrap_or_else(|_| "Failed to read error body".to_string());
-        let error_msg = format!("Mastra server returned error ({}): {}", status, error_text);
-        // Emit error event before returning Err
-        window.emit("chat_stream_error", &error_msg).map_err(|e| format!("Failed to emit error event: {}", e))?;
-        return Err(error_msg);
+        return Err(AppError::Api(format!("OpenAI API error ({}): {}", status, error_text)));
     }
 
-    // Process the stream - use the stream method available in reqwest with tokio_stream
-    let mut stream = res.bytes_stream();
-    let mut buffer = String::with_capacity(1024); // Pre-allocate a decent buffer size
+    let completion: OpenAiChatCompletion = res
+        .json()
+        .await
+        .map_err(|e| AppError::Api(format!("Failed to parse OpenAI response: {}", e)))?;
 
-    // Create a debouncer to coalesce small updates and reduce UI renders
-    let mut last_emit = std::time::Instant::now();
-    let mut accumulated_text = String::with_capacity(512);  
+    // Check if choices are available and get the first one
+    let content = completion
+        .choices
+        .into_iter()
+        .next()
+        .map(|choice| choice.message.content.unwrap_or_default())
+        .ok_or_else(|| AppError::Api("OpenAI response did not contain any choices.".to_string()))?;
+
+    // Compatible servers may omit usage; report zero rather than failing the reply
+    let (prompt_tokens, completion_tokens) = completion
+        .usage
+        .map(|usage| (usage.prompt_tokens, usage.completion_tokens))
+        .unwrap_or_default();
+    Ok(ChatResponse {
+        content,
+        model: completion.model.unwrap_or(model),
+        prompt_tokens,
+        completion_tokens,
+    })
+}
+
+// Streaming variant of `chat`: emits `chat_chunk` and `chat_stream_end` like chat_mastra,
+// so the frontend handles both backends the same way. Text only; use `chat` for images.
+// Pass a `request_id` to stop it with cancel_chat_mastra, which cancels any chat stream by id.
+#[tauri::command]
+#[tracing::instrument(skip_all, fields(model = ?model))]
+async fn chat_stream<R: Runtime>(
+    app: AppHandle<R>,
+    config: State<'_, AppConfig>,
+    prompt: String,
+    messages_history: Vec<ChatMessage>,
+    model: Option<String>,
+    system: Option<String>,
+    temperature: Option<f32>,
+    request_id: Option<String>,
+    streams: State<'_, ChatStreams>,
+) -> std::result::Result<String, AppError> {
+    let model = resolve_openai_model(model, temperature)?;
+    let key = config.openai_api_key()?;
+    let window = chat_window(&app).map_err(AppError::NotFound)?;
+    let request_id = request_id.unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let mut messages: Vec<serde_json::Value> = Vec::new();
+    if let Some(system) = system.filter(|s| !s.trim().is_empty()) {
+        messages.push(serde_json::json!({ "role": "system", "content": system }));
+    }
+    for msg in messages_history {
+        messages.push(serde_json::json!({ "role": msg.role, "content": msg.content }));
+    }
+    messages.push(serde_json::json!({ "role": "user", "content": prompt }));
+
+    let mut request_body = serde_json::json!({
+        "model": model,
+        "messages": messages,
+        "stream": true,
+    });
+    if let Some(t) = temperature {
+        request_body["temperature"] = serde_json::json!(t);
+    }
+
+    // Register a cancellation token so cancel_chat_mastra, cancel_all and exit can stop this stream
+    let cancel_token = CancellationToken::new();
+    streams.0.lock()
+        .map_err(|e| AppError::Internal(format!("Failed to lock stream registry: {}", e)))?
+        .insert(request_id.clone(), cancel_token.clone());
+    let _registration = TokenRegistration {
+        registry: streams.0.clone(),
+        id: request_id.clone(),
+    };
+
+    let request = openai_http_client(&config.proxy)
+        .map_err(AppError::InvalidInput)?
+        .post(config.openai_chat_completions_url())
+        .bearer_auth(&key)
+        .json(&request_body)
+        .send();
+    let res = tokio::select! {
+        _ = cancel_token.cancelled() => {
+            window.emit("chat_stream_cancelled", &request_id)
+                .map_err(|e| AppError::Internal(format!("Failed to emit stream cancelled event: {}", e)))?;
+            return Ok(String::new());
+        }
+        res = request => res.map_err(|e| AppError::Network(format!("Failed to start OpenAI stream: {}", e)))?,
+    };
+
+    let status = res.status();
+    if !status.is_success() {
+        let error_text = res.text().await.unwrap_or_else(|_| "Failed to read error body".to_string());
+        return Err(AppError::Api(format!("OpenAI API error ({}): {}", status, error_text)));
+    }
+
+    // Server-sent events: `data: {json}` lines, ending with `data: [DONE]`.
+    // Buffer raw bytes so a multi-byte character split across chunks stays intact.
+    let mut stream = res.bytes_stream();
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut coalescer = ChunkCoalescer::new(Some(&window), true, None);
+    'read: loop {
+        // Wait for the next chunk unless the stream is cancelled first
+        let chunk = tokio::select! {
+            _ = cancel_token.cancelled() => {
+                info!("Stream {} cancelled", request_id);
+                coalescer.flush().map_err(AppError::Internal)?;
+                window.emit("chat_stream_cancelled", &request_id)
+                    .map_err(|e| AppError::Internal(format!("Failed to emit stream cancelled event: {}", e)))?;
+                return Ok(coalescer.into_text());
+            }
+            chunk = stream.next() => chunk,
+        };
+        let Some(chunk) = chunk else { break };
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                let error_msg = format!("Error reading stream from OpenAI: {}", e);
+                error!("{}", error_msg);
+                coalescer.flush().map_err(AppError::Internal)?;
+                window.emit("chat_stream_error", &error_msg)
+                    .map_err(|e| AppError::Internal(format!("Failed to emit stream error event: {}", e)))?;
+                return Err(AppError::Network(error_msg));
+            }
+        };
+        buffer.extend_from_slice(&chunk);
+
+        while let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim().strip_prefix("data:").map(str::trim) else {
+                continue;
+            };
+            if data == "[DONE]" {
+                break 'read;
+            }
+            match serde_json::from_str::<OpenAiStreamChunk>(data) {
+                Ok(chunk) => {
+                    if let Some(text) = chunk.choices.first().and_then(|choice| choice.delta.content.as_deref()) {
+                        coalescer.push(text).map_err(AppError::Internal)?;
+                    }
+                }
+                Err(e) => warn!("Failed to parse OpenAI stream event {}: {}", data, e),
+            }
+        }
+    }
+
+    coalescer.flush().map_err(AppError::Internal)?;
+    window.emit("chat_stream_end", ()).map_err(|e| AppError::Internal(format!("Failed to emit stream end event: {}", e)))?;
+    Ok(coalescer.into_text())
+}
+
+// Mastra dev server default, overridable via MASTRA_BASE_URL
+const DEFAULT_MASTRA_BASE_URL: &str = "http://localhost:4111";
+
+// Connecting should be quick even when a streamed response legitimately runs for minutes
+const DEFAULT_MASTRA_CONNECT_TIMEOUT_MS: u64 = 10_000;
+// Overall limit for short, non-streaming Mastra calls
+const MASTRA_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// Redirect hops followed before giving up. Tighter than reqwest's default of 10: a Mastra
+// server sits behind at most a proxy or two, so a longer chain is a loop and should fail fast.
+const MASTRA_MAX_REDIRECTS: usize = 5;
+
+// Mastra's data stream prefixes are a single digit or lowercase letter
+fn is_mastra_stream_prefix(prefix: char) -> bool {
+    prefix.is_ascii_digit() || prefix.is_ascii_lowercase()
+}
+
+// First character of a JSON value: string, object, array, number, true, false or null
+fn is_json_token_start(c: char) -> bool {
+    matches!(c, '"' | '{' | '[' | '-' | '0'..='9' | 't' | 'f' | 'n')
+}
+
+// Mastra frames each line as `<prefix>:<json>` (or SSE `data:`). Returns whether the body
+// looks framed, or None while too little of it has arrived to tell.
+fn detect_stream_framing(buffer: &str) -> Option<bool> {
+    let start = buffer.trim_start();
+    let mut chars = start.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(prefix), None, _) | (Some(prefix), Some(':'), None) if is_mastra_stream_prefix(prefix) => None,
+        (Some(prefix), Some(':'), Some(token)) if is_mastra_stream_prefix(prefix) => Some(is_json_token_start(token)),
+        _ if "data:".starts_with(start) => None,
+        _ => Some(start.starts_with("data:")),
+    }
+}
+
+// --- Proxy ---
+
+// Route a client through the configured HTTPS / HTTP proxy, honoring NO_PROXY for exclusions
+fn with_proxy(settings: &ProxySettings, mut builder: reqwest::ClientBuilder) -> std::result::Result<reqwest::ClientBuilder, String> {
+    let no_proxy = || settings.no_proxy.as_deref().and_then(reqwest::NoProxy::from_string);
+    if let Some(url) = &settings.https_proxy {
+        let proxy = reqwest::Proxy::https(url)
+            .map_err(|e| format!("Invalid HTTPS_PROXY '{}': {}", url, e))?
+            .no_proxy(no_proxy());
+        builder = builder.proxy(proxy);
+    }
+    if let Some(url) = &settings.http_proxy {
+        let proxy = reqwest::Proxy::http(url)
+            .map_err(|e| format!("Invalid HTTP_PROXY '{}': {}", url, e))?
+            .no_proxy(no_proxy());
+        builder = builder.proxy(proxy);
+    }
+    Ok(builder)
+}
+
+// Create a client with optimized timeout and pool settings, shared by all Mastra calls.
+// `timeout` bounds the whole response, so leave it unset for streams.
+fn mastra_http_client(
+    proxy: &ProxySettings,
+    connect_timeout: Duration,
+    timeout: Option<Duration>,
+) -> std::result::Result<reqwest::Client, String> {
+    let mut builder = with_proxy(proxy, reqwest::Client::builder())?
+        .connect_timeout(connect_timeout)
+        .redirect(reqwest::redirect::Policy::limited(MASTRA_MAX_REDIRECTS))
+        .pool_max_idle_per_host(10);       // Keep connections alive for reuse
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    builder
+        .build()
+        .map_err(|e| format!("Failed to build HTTP client: {}", e))
+}
+
+// Validate caller-supplied request headers up front so a bad name fails before anything is sent
+fn build_header_map(headers: &HashMap<String, String>) -> std::result::Result<reqwest::header::HeaderMap, String> {
+    use reqwest::header::{HeaderName, HeaderValue};
+
+    let mut map = reqwest::header::HeaderMap::with_capacity(headers.len());
+    for (name, value) in headers {
+        let header_name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| format!("Invalid header name '{}': {}", name, e))?;
+        let header_value = HeaderValue::from_str(value)
+            .map_err(|e| format!("Invalid value for header '{}': {}", name, e))?;
+        map.insert(header_name, header_value);
+    }
+    Ok(map)
+}
+
+// Delays between retries of the initial Mastra request, mirroring the R2 retry config
+const MASTRA_RETRY_BACKOFF_MS: [u64; 3] = [200, 400, 800];
+
+// Agent names are interpolated into the URL path, so reject anything that could escape it
+fn validate_agent_name(agent: &str) -> std::result::Result<(), String> {
+    if agent.is_empty() {
+        return Err("Agent name cannot be empty.".to_string());
+    }
+    if agent.contains(['/', '\\', '?', '#', '%']) || agent.contains("..") {
+        return Err(format!("Invalid agent name '{}': must not contain path separators", agent));
+    }
+    Ok(())
+}
+
+// Payload for `conversation_chunk` events, so the UI can route text to the right thread
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ConversationChunk<'a> {
+    conversation_id: &'a str,
+    text: &'a str,
+}
+
+// Decode the complete UTF-8 prefix of `bytes`, leaving a trailing partial sequence in place
+// for the next chunk. Bytes that can never be valid are replaced with U+FFFD.
+fn drain_utf8(bytes: &mut Vec<u8>) -> String {
+    let mut decoded = String::with_capacity(bytes.len());
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => {
+                decoded.push_str(text);
+                bytes.clear();
+                return decoded;
+            }
+            Err(e) => {
+                let valid_up_to = e.valid_up_to();
+                decoded.push_str(std::str::from_utf8(&bytes[..valid_up_to]).unwrap_or_default());
+                match e.error_len() {
+                    // Incomplete sequence at the end: wait for more bytes
+                    None => {
+                        bytes.drain(..valid_up_to);
+                        return decoded;
+                    }
+                    Some(invalid_len) => {
+                        decoded.push(char::REPLACEMENT_CHARACTER);
+                        bytes.drain(..valid_up_to + invalid_len);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Default chunk-coalescing thresholds for chat_chunk events
+const DEFAULT_MIN_CHUNK_CHARS: usize = 50;
+const DEFAULT_MAX_DEBOUNCE_MS: u64 = 100;
+
+// Coalesces small text deltas into `chat_chunk` events and keeps the full response text.
+// With events disabled it only accumulates, for callers that want the whole message back.
+struct ChunkCoalescer<'a, R: Runtime> {
+    // None when nobody is listening, e.g. a non-streaming chat_mastra call
+    window: Option<&'a tauri::WebviewWindow<R>>,
+    emit_events: bool,
+    conversation_id: Option<String>,
+    // Optional copy of the text on disk; dropped after the first write error
+    file: Option<std::io::BufWriter<std::fs::File>>,
+    // Emit once this many characters are pending, or this long has passed
+    min_chunk_chars: usize,
+    max_debounce: Duration,
+    pending: String,
+    full_text: String,
+    last_emit: std::time::Instant,
+}
+
+impl<'a, R: Runtime> ChunkCoalescer<'a, R> {
+    fn new(window: Option<&'a tauri::WebviewWindow<R>>, emit_events: bool, conversation_id: Option<String>) -> Self {
+        Self {
+            window,
+            emit_events,
+            conversation_id,
+            file: None,
+            min_chunk_chars: DEFAULT_MIN_CHUNK_CHARS,
+            max_debounce: Duration::from_millis(DEFAULT_MAX_DEBOUNCE_MS),
+            pending: String::with_capacity(512),
+            full_text: String::new(),
+            last_emit: std::time::Instant::now(),
+        }
+    }
+
+    // Also write the text to `path` as it arrives
+    fn save_to(&mut self, path: &str) {
+        match std::fs::File::create(path) {
+            Ok(file) => self.file = Some(std::io::BufWriter::new(file)),
+            Err(e) => self.report_file_error(format!("Failed to create '{}': {}", path, e)),
+        }
+    }
+
+    // File problems shouldn't stop the stream, so surface them and keep going without the file
+    fn report_file_error(&mut self, error_msg: String) {
+        error!("{}", error_msg);
+        self.file = None;
+        let _ = emit_to_chat_window(self.window, "chat_stream_error", &error_msg);
+    }
+
+    fn finish_file(&mut self) {
+        if let Some(file) = self.file.as_mut() {
+            if let Err(e) = std::io::Write::flush(file) {
+                self.report_file_error(format!("Failed to flush saved response: {}", e));
+            }
+        }
+    }
+
+    fn push(&mut self, text: &str) -> std::result::Result<(), String> {
+        self.full_text.push_str(text);
+        if let Some(file) = self.file.as_mut() {
+            if let Err(e) = std::io::Write::write_all(file, text.as_bytes()) {
+                self.report_file_error(format!("Failed to write saved response: {}", e));
+            }
+        }
+        if !self.emit_events {
+            return Ok(());
+        }
+        self.pending.push_str(text);
+
+        // Emit if we have enough text or enough time has passed
+        let now = std::time::Instant::now();
+        if self.pending.len() > self.min_chunk_chars || now.duration_since(self.last_emit) > self.max_debounce {
+            self.emit_pending()?;
+            self.last_emit = now;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::result::Result<(), String> {
+        if !self.pending.is_empty() {
+            self.emit_pending()?;
+        }
+        Ok(())
+    }
+
+    fn emit_pending(&mut self) -> std::result::Result<(), String> {
+        emit_to_chat_window(self.window, "chat_chunk", &self.pending)?;
+        if let Some(conversation_id) = &self.conversation_id {
+            emit_to_chat_window(self.window, "conversation_chunk", ConversationChunk {
+                conversation_id,
+                text: &self.pending,
+            })?;
+        }
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn text(&self) -> &str {
+        &self.full_text
+    }
+
+    fn into_text(self) -> String {
+        self.full_text
+    }
+}
+
+// How often a streaming response is written to its note
+const NOTE_SAVE_INTERVAL: Duration = Duration::from_millis(500);
+
+// Keeps a note's body in step with a streaming response, so a crash mid-generation
+// still leaves the partial text behind
+struct NoteSync {
+    pool: sqlx::SqlitePool,
+    note_id: i64,
+    saved_len: usize,
+    last_save: std::time::Instant,
+}
+
+impl NoteSync {
+    fn new(pool: sqlx::SqlitePool, note_id: i64) -> Self {
+        Self { pool, note_id, saved_len: 0, last_save: std::time::Instant::now() }
+    }
+
+    // Write only if the text grew and the interval has passed
+    async fn save_if_due(&mut self, text: &str) {
+        if text.len() != self.saved_len && self.last_save.elapsed() >= NOTE_SAVE_INTERVAL {
+            self.save(text).await;
+        }
+    }
+
+    // Failed writes are logged; the stream itself carries on
+    async fn save(&mut self, text: &str) {
+        match sqlx::query("UPDATE notes SET body = ? WHERE id = ?")
+            .bind(text)
+            .bind(self.note_id)
+            .execute(&self.pool)
+            .await
+        {
+            Ok(_) => self.saved_len = text.len(),
+            Err(e) => warn!("Failed to save response to note {}: {}", self.note_id, e),
+        }
+        self.last_save = std::time::Instant::now();
+    }
+}
+
+// Minimum gap between `chat_stream_error` events raised by the '3' prefix
+const STREAM_ERROR_DEBOUNCE: Duration = Duration::from_millis(250);
+
+// Batches the server's in-stream errors so a burst becomes one event, and drops
+// repeats of the message just received
+#[derive(Default)]
+struct StreamErrorDebouncer {
+    pending: Vec<String>,
+    last_message: Option<String>,
+    last_emit: Option<std::time::Instant>,
+}
+
+impl StreamErrorDebouncer {
+    fn push<R: Runtime>(&mut self, window: Option<&tauri::WebviewWindow<R>>, message: &str) -> std::result::Result<(), String> {
+        if self.last_message.as_deref() == Some(message) {
+            return Ok(());
+        }
+        self.last_message = Some(message.to_string());
+        self.pending.push(message.to_string());
+        self.flush_if_due(window)
+    }
+
+    fn flush_if_due<R: Runtime>(&mut self, window: Option<&tauri::WebviewWindow<R>>) -> std::result::Result<(), String> {
+        if self.last_emit.map_or(true, |at| at.elapsed() >= STREAM_ERROR_DEBOUNCE) {
+            self.flush(window)?;
+        }
+        Ok(())
+    }
+
+    fn flush<R: Runtime>(&mut self, window: Option<&tauri::WebviewWindow<R>>) -> std::result::Result<(), String> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        emit_to_chat_window(window, "chat_stream_error", self.pending.join("\n"))?;
+        self.pending.clear();
+        self.last_emit = Some(std::time::Instant::now());
+        Ok(())
+    }
+}
+
+// Sent after the partial reply when a dropped stream is resumed
+const MASTRA_RESUME_PROMPT: &str =
+    "Your previous response was cut off. Continue it exactly where it stopped, without repeating any of it.";
+
+// Payload for `chat_stream_resumed`, sent once a dropped stream has reconnected
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ChatStreamResumed<'a> {
+    request_id: &'a str,
+    received_chars: usize,
+}
+
+// Connection drops and stalls mid-body are worth one reconnect; anything else is not
+fn is_recoverable_stream_error(error: &reqwest::Error) -> bool {
+    error.is_body() || error.is_timeout()
+}
+
+// The original request plus the partial reply as an assistant turn, then a request to carry on.
+// With nothing received yet, the original request is simply sent again.
+fn mastra_resume_body(request_body: &serde_json::Value, partial: &str) -> serde_json::Value {
+    let mut body = request_body.clone();
+    if partial.is_empty() {
+        return body;
+    }
+    if let Some(messages) = body["messages"].as_array_mut() {
+        messages.push(serde_json::json!({
+            "role": "assistant",
+            "content": [{ "type": "text", "text": partial }]
+        }));
+        messages.push(serde_json::json!({
+            "role": "user",
+            "content": [{ "type": "text", "text": MASTRA_RESUME_PROMPT }]
+        }));
+    }
+    body
+}
+
+// Send a Mastra stream request, retrying connection failures and timeouts with backoff.
+// HTTP error statuses are reported on `chat_stream_error` and never retried.
+// Returns None when the token is cancelled during a backoff wait.
+async fn send_mastra_request<R: Runtime>(
+    window: Option<&tauri::WebviewWindow<R>>,
+    config: &AppConfig,
+    client: &reqwest::Client,
+    endpoint: &str,
+    headers: &reqwest::header::HeaderMap,
+    body: &serde_json::Value,
+    cancel_token: &CancellationToken,
+) -> std::result::Result<Option<reqwest::Response>, AppError> {
+    let mut attempt = 0;
+    let res = loop {
+        let request = config.with_mastra_auth(client.post(endpoint))
+            .headers(headers.clone())
+            .json(body);
+        match request.send().await {
+            Ok(res) => break res,
+            Err(e) if (e.is_connect() || e.is_timeout()) && attempt < MASTRA_RETRY_BACKOFF_MS.len() => {
+                let delay = Duration::from_millis(MASTRA_RETRY_BACKOFF_MS[attempt]);
+                attempt += 1;
+                error!("Mastra request failed ({}), retry {} in {:?}", e, attempt, delay);
+                emit_to_chat_window(window, "chat_stream_retry", attempt).map_err(AppError::Internal)?;
+
+                tokio::select! {
+                    _ = cancel_token.cancelled() => return Ok(None),
+                    _ = tokio::time::sleep(delay) => {}
+                }
+            }
+            Err(e) => return Err(AppError::Network(format!("Failed to send request to Mastra server: {}", e))),
+        }
+    };
+
+    let status = res.status();
+    info!("Received response from Mastra. Status: {}", status);
+
+    if !status.is_success() {
+        let error_text = res.text().await.unwrap_or_else(|_| "Failed to read error body".to_string());
+        let error_msg = format!("Mastra server returned error ({}): {}", status, error_text);
+        // Emit error event before returning Err
+        emit_to_chat_window(window, "chat_stream_error", &error_msg).map_err(AppError::Internal)?;
+        return Err(AppError::Api(error_msg));
+    }
+
+    if res.url().as_str() != endpoint {
+        info!("Mastra request was redirected to {}", res.url());
+    }
+    Ok(Some(res))
+}
+
+// --- MODIFIED COMMAND ---
+#[tauri::command]
+#[tracing::instrument(skip_all, fields(agent = %agent, request_id = %request_id))]
+async fn chat_mastra<R: Runtime>(
+    prompt: String,
+    messages_history: Vec<ChatMessage>,
+    image_url: Option<String>,
+    image_urls: Option<Vec<String>>,
+    agent: String,
+    request_id: String,
+    stream: bool,
+    conversation_id: Option<String>,
+    save_path: Option<String>,
+    min_chunk_chars: Option<usize>,
+    max_debounce_ms: Option<u64>,
+    headers: Option<HashMap<String, String>>,
+    connect_timeout_ms: Option<u64>,
+    timeout_ms: Option<u64>,
+    note_id: Option<i64>,
+    app: AppHandle<R>,
+    streams: State<'_, ChatStreams>,
+    config: State<'_, AppConfig>,
+) -> std::result::Result<String, AppError> {
+    // `stream` is shadowed by the response byte stream below
+    let stream_events = stream;
+    validate_agent_name(&agent).map_err(AppError::InvalidInput)?;
+    let extra_headers = build_header_map(&headers.unwrap_or_default()).map_err(AppError::InvalidInput)?;
+    let mastra_endpoint = format!("{}/api/agents/{}/stream", config.mastra_base_url, agent);
+    let client = mastra_http_client(
+        &config.proxy,
+        Duration::from_millis(connect_timeout_ms.unwrap_or(DEFAULT_MASTRA_CONNECT_TIMEOUT_MS)),
+        timeout_ms.map(Duration::from_millis),
+    )
+    .map_err(AppError::InvalidInput)?;
+
+    // Start constructing the messages payload for Mastra
+    let mut final_messages_payload: Vec<serde_json::Value> = Vec::new();
+
+    // Lead with the conversation's stored system prompt, if it has one
+    if let Some(id) = &conversation_id {
+        if let Some(system) = conversation_system_prompt(&app, id).await.map_err(AppError::Database)? {
+            final_messages_payload.push(serde_json::json!({ "role": "system", "content": system }));
+        }
+    }
+
+    // Process history messages (assuming simple text content for now)
+    for msg in messages_history {
+        final_messages_payload.push(serde_json::json!({
+            "role": msg.role,
+            "content": [{ "type": "text", "text": msg.content }]
+        }));
+    }
+
+    // Construct the current user message payload
+    let mut current_user_content: Vec<ContentPart> = Vec::new();
+
+    // Add text part if prompt is not empty
+    if !prompt.trim().is_empty() {
+        current_user_content.push(ContentPart::Text(TextContent {
+            type_field: "text".to_string(),
+            text: prompt.trim().to_string(),
+        }));
+    }
+
+    // Add one image part per URL (now expects pre-signed URLs); the singular
+    // `image_url` is still accepted and goes first
+    for url in image_url.into_iter().chain(image_urls.into_iter().flatten()) {
+         debug!("Image URL received in chat_mastra: {}", url);
+        // Basic validation for URL format might still be useful, but R2 presigned URLs are complex
+        if url.starts_with("https://") {
+            current_user_content.push(ContentPart::ImageUrl(ImageUrlContent {
+                type_field: "image".to_string(),
+                image: url,
+            }));
+        } else {
+            warn!("Provided image_url does not look like a secure pre-signed URL: {}", url);
+            // Decide if you want to proceed or error out if the URL is not HTTPS
+        }
+    }
+
+    // Add the fully constructed user message to the payload
+    if !current_user_content.is_empty() {
+        final_messages_payload.push(serde_json::json!({
+            "role": "user",
+            "content": current_user_content
+        }));
+    } else {
+        // Allow sending empty text prompt if an image URL *is* provided
+        if final_messages_payload.iter().any(|m| m["role"] == "user" && m["content"].as_array().map_or(false, |c| c.iter().any(|p| p["type"] == "image_url"))) {
+             info!("Sending message with only image.");
+        } else {
+             return Err(AppError::InvalidInput("Cannot send an empty message without an image.".to_string()));
+        }
+    }
+
+    // Prepare request body according to Mastra stream API
+    let request_body = serde_json::json!({
+        "messages": final_messages_payload,
+    });
+
+    debug!("Sending request to Mastra stream API. Payload: {}", serde_json::to_string_pretty(&request_body).unwrap_or_default());
+
+    // Events go to the popup or drag-chat window; a non-streaming call only needs the
+    // returned text, so it runs even when neither is open
+    let events_window = if stream_events { Some(chat_window(&app).map_err(AppError::NotFound)?) } else { None };
+    let window = events_window.as_ref();
+
+    // Register a cancellation token so cancel_chat_mastra can stop this stream
+    let cancel_token = CancellationToken::new();
+    streams.0.lock()
+        .map_err(|e| AppError::Internal(format!("Failed to lock stream registry: {}", e)))?
+        .insert(request_id.clone(), cancel_token.clone());
+    let _registration = TokenRegistration {
+        registry: streams.0.clone(),
+        id: request_id.clone(),
+    };
+
+    let Some(res) = send_mastra_request(
+        window,
+        &config,
+        &client,
+        &mastra_endpoint,
+        &extra_headers,
+        &request_body,
+        &cancel_token,
+    )
+    .await?
+    else {
+        emit_to_chat_window(window, "chat_stream_cancelled", &request_id).map_err(AppError::Internal)?;
+        return Ok(String::new());
+    };
+
+    // Process the stream - use the stream method available in reqwest with tokio_stream
+    let mut stream = res.bytes_stream();
+    let mut buffer = String::with_capacity(1024); // Pre-allocate a decent buffer size
+    let mut utf8_carry: Vec<u8> = Vec::new(); // Undecoded tail of the previous chunk
+    // Whether the body uses Mastra's line framing; some proxies hand back plain text instead
+    let mut framed: Option<bool> = None;
+    // A dropped stream is resumed at most once
+    let mut resumed = false;
+
+    // Create a debouncer to coalesce small updates and reduce UI renders
+    let mut coalescer = ChunkCoalescer::new(window, stream_events, conversation_id.clone());
+    if let Some(path) = &save_path {
+        coalescer.save_to(path);
+    }
+    if let Some(chars) = min_chunk_chars {
+        coalescer.min_chunk_chars = chars;
+    }
+    if let Some(ms) = max_debounce_ms {
+        coalescer.max_debounce = Duration::from_millis(ms);
+    }
+    let mut stream_finish: Option<MastraStreamFinish> = None;
+    let mut stream_errors = StreamErrorDebouncer::default();
+    let mut note_sync = match note_id {
+        Some(note_id) => Some(NoteSync::new(notes_db(&app).await.map_err(AppError::Database)?, note_id)),
+        None => None,
+    };
+
+    loop {
+        // Wait for the next chunk unless the frontend cancels first
+        let item = tokio::select! {
+            _ = cancel_token.cancelled() => {
+                info!("Stream {} cancelled", request_id);
+                stream_errors.flush(window).map_err(AppError::Internal)?;
+                if let Some(note_sync) = note_sync.as_mut() {
+                    note_sync.save(coalescer.text()).await;
+                }
+                emit_to_chat_window(window, "chat_stream_cancelled", &request_id).map_err(AppError::Internal)?;
+                return Ok(coalescer.into_text());
+            }
+            item = stream.next() => item,
+        };
+        let Some(item) = item else { break };
 
-    while let Some(item) = stream.next().await {
         match item {
             Ok(chunk_bytes) => {
-                // Convert the bytes to a string
-                let chunk_str = String::from_utf8_lossy(&chunk_bytes).to_string();
-                
+                // Convert the bytes to a string, holding back any multi-byte
+                // character split across chunk boundaries until the rest arrives
+                utf8_carry.extend_from_slice(&chunk_bytes);
+                let chunk_str = drain_utf8(&mut utf8_carry);
+
                 // Append to our buffer
                 buffer.push_str(&chunk_str);
 
+                // Unframed bodies are passed through as text as they arrive
+                if framed.is_none() {
+                    framed = detect_stream_framing(&buffer);
+                    if framed == Some(false) {
+                        warn!("Mastra response is not line-framed, treating it as plain text");
+                    }
+                }
+                if framed == Some(false) {
+                    coalescer.push(&buffer).map_err(AppError::Internal)?;
+                    buffer.clear();
+                }
+
                 // Process any complete lines
                 while let Some(pos) = buffer.find('\n') {
                     let line = buffer[..pos].trim().to_string();
                     // More efficient substring extraction
                     buffer.drain(..=pos);
 
-                    // Skip empty lines
-                    if line.is_empty() {
-                        continue;
-                    }
+                    // Skip empty lines
+                    if line.is_empty() {
+                        continue;
+                    }
+
+                    // Parse the Mastra streaming format with different prefixes
+                    if line.len() >= 2 && line.chars().nth(1) == Some(':') {
+                        let prefix = line.chars().next().unwrap_or('?');
+                        let content = &line[2..];
+
+                        match prefix {
+                            'f' => {
+                                // First message, typically contains messageId
+                                debug!("Message start: {}", content);
+                                // A resumed stream continues the message the frontend already started
+                                match serde_json::from_str::<MastraStreamStart>(content) {
+                                    Ok(start) if stream_events && !resumed => {
+                                        emit_to_chat_window(window, "chat_stream_start", &start).map_err(AppError::Internal)?;
+                                    }
+                                    Ok(_) => {}
+                                    Err(e) => warn!("Failed to parse stream start payload: {}", e),
+                                }
+                            },
+                            '0' => {
+                                // Text content chunk: a JSON string literal, so serde handles the escapes
+                                match serde_json::from_str::<String>(content) {
+                                    // Accumulate text and only emit after a reasonable batch or time
+                                    Ok(text) => coalescer.push(&text).map_err(AppError::Internal)?,
+                                    Err(e) => warn!("Failed to parse text chunk {}: {}", content, e),
+                                }
+                            },
+                            'e' | 'd' => {
+                                // End message or Done message
+                                debug!("Stream end marker: {} - {}", prefix, content);
+
+                                // Keep the latest usage/finishReason; 'd' arrives last and wins
+                                match serde_json::from_str::<MastraStreamFinish>(content) {
+                                    Ok(mut finish) => {
+                                        finish.truncated = finish.finish_reason.as_deref() == Some("length");
+                                        stream_finish = Some(finish);
+                                    }
+                                    Err(e) => warn!("Failed to parse stream end payload: {}", e),
+                                }
+                                
+                                // Emit any remaining accumulated text
+                                coalescer.flush().map_err(AppError::Internal)?;
+                            },
+                            '9' => {
+                                // Tool call with its full arguments
+                                match serde_json::from_str::<MastraToolCall>(content) {
+                                    Ok(call) if stream_events => {
+                                        coalescer.flush().map_err(AppError::Internal)?;
+                                        emit_to_chat_window(window, "chat_tool_call", &call).map_err(AppError::Internal)?;
+                                    }
+                                    Ok(_) => {}
+                                    Err(e) => warn!("Failed to parse tool call payload: {}", e),
+                                }
+                            },
+                            'a' => {
+                                // Result of an earlier tool call
+                                match serde_json::from_str::<MastraToolResult>(content) {
+                                    Ok(result) if stream_events => {
+                                        emit_to_chat_window(window, "chat_tool_result", &result).map_err(AppError::Internal)?;
+                                    }
+                                    Ok(_) => {}
+                                    Err(e) => warn!("Failed to parse tool result payload: {}", e),
+                                }
+                            },
+                            '2' | '8' => {
+                                // Data and message annotations aren't surfaced yet
+                                debug!("Stream data ({}): {}", prefix, content);
+                            },
+                            '3' => {
+                                // Error message
+                                warn!("Mastra stream error: {}", content);
+                                // Strip quotes if present in error message
+                                let error_content = if content.starts_with('"') && content.ends_with('"') && content.len() >= 2 {
+                                    &content[1..content.len()-1]
+                                } else {
+                                    content
+                                };
+                                stream_errors.push(window, error_content).map_err(AppError::Internal)?;
+                            },
+                            _ => {
+                                // Unknown prefix, try to extract useful content
+                                debug!("Unknown prefix: {} - content: {}", prefix, content);
+                            }
+                        }
+                    } else if line.starts_with("data: ") {
+                        // Handle standard SSE format as fallback
+                        let data = &line[6..]; // Skip "data: " prefix
+
+                        if data == "[DONE]" {
+                            debug!("Stream complete marker received");
+                            // Emit any remaining text
+                            coalescer.flush().map_err(AppError::Internal)?;
+                            continue;
+                        }
+
+                        // Try to parse data content
+                        if let Ok(json_value) = serde_json::from_str::<serde_json::Value>(data) {
+                            if let Some(text) = json_value.get("text").and_then(|t| t.as_str()) {
+                                coalescer.push(text).map_err(AppError::Internal)?;
+                            }
+                        } else if !data.is_empty() {
+                            coalescer.push(data).map_err(AppError::Internal)?;
+                        }
+                    }
+                }
+                // Errors held back by the debounce go out once their window has passed
+                stream_errors.flush_if_due(window).map_err(AppError::Internal)?;
+                if let Some(note_sync) = note_sync.as_mut() {
+                    note_sync.save_if_due(coalescer.text()).await;
+                }
+            }
+            Err(e) if !resumed && is_recoverable_stream_error(&e) => {
+                // Reconnect once, asking the agent to continue from the text received so far
+                resumed = true;
+                warn!("Mastra stream dropped after {} chars ({}), resuming", coalescer.text().len(), e);
+                coalescer.flush().map_err(AppError::Internal)?;
+                stream_errors.flush(window).map_err(AppError::Internal)?;
+                let resume_body = mastra_resume_body(&request_body, coalescer.text());
+                match send_mastra_request(
+                    window,
+                    &config,
+                    &client,
+                    &mastra_endpoint,
+                    &extra_headers,
+                    &resume_body,
+                    &cancel_token,
+                )
+                .await
+                {
+                    Ok(Some(res)) => {
+                        emit_to_chat_window(window, "chat_stream_resumed", ChatStreamResumed {
+                            request_id: &request_id,
+                            received_chars: coalescer.text().chars().count(),
+                        })
+                        .map_err(AppError::Internal)?;
+                        // Whatever was left of the dropped body is discarded
+                        stream = res.bytes_stream();
+                        buffer.clear();
+                        utf8_carry.clear();
+                        framed = None;
+                    }
+                    Ok(None) => {
+                        info!("Stream {} cancelled while resuming", request_id);
+                        if let Some(note_sync) = note_sync.as_mut() {
+                            note_sync.save(coalescer.text()).await;
+                        }
+                        emit_to_chat_window(window, "chat_stream_cancelled", &request_id).map_err(AppError::Internal)?;
+                        return Ok(coalescer.into_text());
+                    }
+                    Err(resume_error) => {
+                        if let Some(note_sync) = note_sync.as_mut() {
+                            note_sync.save(coalescer.text()).await;
+                        }
+                        let stream_error_msg = format!("Error reading stream from Mastra: {} (resume failed: {})", e, resume_error);
+                        error!("{}", stream_error_msg);
+                        emit_to_chat_window(window, "chat_stream_error", &stream_error_msg).map_err(AppError::Internal)?;
+                        return Err(AppError::Network(stream_error_msg));
+                    }
+                }
+            }
+            Err(e) => {
+                stream_errors.flush(window).map_err(AppError::Internal)?;
+                if let Some(note_sync) = note_sync.as_mut() {
+                    note_sync.save(coalescer.text()).await;
+                }
+                // Error reading from the stream
+                let stream_error_msg = format!("Error reading stream from Mastra: {}", e);
+                error!("{}", stream_error_msg);
+                emit_to_chat_window(window, "chat_stream_error", &stream_error_msg).map_err(AppError::Internal)?;
+                // Terminate processing on stream error
+                return Err(AppError::Network(stream_error_msg));
+            }
+        }
+    }
+
+    // A body too short to classify is plain text too
+    if framed.is_none() && !buffer.trim().is_empty() {
+        coalescer.push(&buffer).map_err(AppError::Internal)?;
+    }
+
+    // Emit any remaining text and errors before signaling the end
+    coalescer.flush().map_err(AppError::Internal)?;
+    stream_errors.flush(window).map_err(AppError::Internal)?;
+    coalescer.finish_file();
+    if let Some(note_sync) = note_sync.as_mut() {
+        note_sync.save(coalescer.text()).await;
+    }
+
+    // Bump the conversation so it sorts first in list_conversations (best effort)
+    if let Some(conversation_id) = &conversation_id {
+        if let Ok(pool) = notes_db(&app).await {
+            if let Err(e) = sqlx::query("UPDATE conversations SET updated_at = CURRENT_TIMESTAMP WHERE id = ?")
+                .bind(conversation_id)
+                .execute(&pool)
+                .await
+            {
+                warn!("Failed to touch conversation {}: {}", conversation_id, e);
+            }
+        }
+    }
+
+    // Signal the end of the stream
+    if stream_events {
+        if let Some(finish) = &stream_finish {
+            emit_to_chat_window(window, "chat_stream_complete", finish).map_err(AppError::Internal)?;
+        }
+        debug!("Emitting stream end");
+        emit_to_chat_window(window, "chat_stream_end", ()).map_err(AppError::Internal)?;
+    }
+    // Return the complete assistant message; non-streaming callers rely on this
+    Ok(coalescer.into_text())
+}
+// --- /MODIFIED COMMAND ---
+
+// Agent ids registered on the Mastra server, for the agent picker
+#[tauri::command]
+async fn list_mastra_agents(config: State<'_, AppConfig>) -> std::result::Result<Vec<String>, String> {
+    let client = mastra_http_client(
+        &config.proxy,
+        Duration::from_millis(DEFAULT_MASTRA_CONNECT_TIMEOUT_MS),
+        Some(MASTRA_REQUEST_TIMEOUT),
+    )?;
+    let url = format!("{}/api/agents", config.mastra_base_url);
+
+    let res = config.with_mastra_auth(client.get(&url))
+        .send()
+        .await
+        .map_err(|e| format!("Failed to reach Mastra server: {}", e))?;
+
+    let status = res.status();
+    if !status.is_success() {
+        let error_text = res.text().await.unwrap_or_else(|_| "Failed to read error body".to_string());
+        return Err(format!("Mastra server returned error ({}): {}", status, error_text));
+    }
+
+    let body: serde_json::Value = res.json()
+        .await
+        .map_err(|e| format!("Failed to parse Mastra agents response: {}", e))?;
+
+    // Mastra returns an object keyed by agent id; accept an array of agents too
+    let mut agents: Vec<String> = match body {
+        serde_json::Value::Object(map) => map.keys().cloned().collect(),
+        serde_json::Value::Array(items) => items
+            .iter()
+            .filter_map(|item| item.get("id").or_else(|| item.get("name")))
+            .filter_map(|id| id.as_str().map(str::to_string))
+            .collect(),
+        other => return Err(format!("Unexpected Mastra agents response: {}", other)),
+    };
+    agents.sort();
+    Ok(agents)
+}
+
+#[tauri::command]
+fn cancel_chat_mastra(request_id: String, streams: State<'_, ChatStreams>) -> std::result::Result<bool, String> {
+    let streams = streams.0.lock().map_err(|e| format!("Failed to lock stream registry: {}", e))?;
+    // Returns false when the stream already finished or never existed
+    match streams.get(&request_id) {
+        Some(token) => {
+            token.cancel();
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+// Define the structure for the return value
+#[derive(Serialize)]
+struct UploadResult {
+    key: String,
+    url: String,
+    // Content type the object was stored with; listings don't report it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    // On-disk copy of the file, when one was kept
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_path: Option<String>,
+}
+
+// Environment variables required for R2 uploads
+const R2_ENV_VARS: [&str; 4] = ["R2_ACCOUNT_ID", "R2_ACCESS_KEY_ID", "R2_SECRET_ACCESS_KEY", "R2_BUCKET_NAME"];
+
+impl R2Settings {
+    fn missing_env_vars(&self) -> Vec<&'static str> {
+        let values = [&self.account_id, &self.access_key_id, &self.secret_access_key, &self.bucket_name];
+        R2_ENV_VARS
+            .iter()
+            .zip(values)
+            // An explicit S3 endpoint stands in for the R2 account id
+            .filter(|(name, _)| !(self.endpoint_url.is_some() && **name == "R2_ACCOUNT_ID"))
+            .filter(|(_, value)| value.is_none())
+            .map(|(name, _)| *name)
+            .collect()
+    }
+}
+
+// Lets the frontend disable upload UI when R2 isn't set up
+#[tauri::command]
+fn r2_configured(r2: State<'_, R2State>) -> bool {
+    r2.0.is_some()
+}
+
+// S3 client pointed at R2, together with the bucket it targets
+#[derive(Clone)]
+struct R2Client {
+    client: S3Client,
+    bucket_name: String,
+}
+
+// R2 client built once during setup; None when R2 isn't configured
+struct R2State(Option<R2Client>);
+
+impl R2State {
+    fn get(&self) -> std::result::Result<R2Client, AppError> {
+        self.0.clone().ok_or_else(|| {
+            AppError::MissingEnvVar(format!("R2 is not configured. Set {} in .env", R2_ENV_VARS.join(", ")))
+        })
+    }
+}
+
+// Lifetime bounds for pre-signed GET URLs; 7 days is the SigV4 maximum
+const DEFAULT_PRESIGN_EXPIRY_SECS: u64 = 1800;
+const MIN_PRESIGN_EXPIRY_SECS: u64 = 60;
+const MAX_PRESIGN_EXPIRY_SECS: u64 = 604_800;
+
+fn presign_expiry(expires_secs: Option<u64>) -> Duration {
+    Duration::from_secs(
+        expires_secs
+            .unwrap_or(DEFAULT_PRESIGN_EXPIRY_SECS)
+            .clamp(MIN_PRESIGN_EXPIRY_SECS, MAX_PRESIGN_EXPIRY_SECS),
+    )
+}
+
+// --- Proxy-aware HTTP client for the AWS SDK ---
+
+// Hands S3 requests to a reqwest client built with `with_proxy`
+#[derive(Debug)]
+struct ProxyHttpClient(ProxySettings);
+
+impl HttpClient for ProxyHttpClient {
+    fn http_connector(&self, settings: &HttpConnectorSettings, _components: &RuntimeComponents) -> SharedHttpConnector {
+        let mut builder = with_proxy(&self.0, reqwest::Client::builder()).unwrap_or_else(|e| {
+            warn!("{}; R2 requests will not use a proxy", e);
+            reqwest::Client::builder()
+        });
+        if let Some(connect_timeout) = settings.connect_timeout() {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+        if let Some(read_timeout) = settings.read_timeout() {
+            builder = builder.read_timeout(read_timeout);
+        }
+        let client = builder.build().unwrap_or_else(|e| {
+            warn!("Failed to build proxy HTTP client, falling back to defaults: {}", e);
+            reqwest::Client::new()
+        });
+        SharedHttpConnector::new(ProxyHttpConnector { client })
+    }
+}
+
+#[derive(Debug)]
+struct ProxyHttpConnector {
+    client: reqwest::Client,
+}
+
+impl HttpConnector for ProxyHttpConnector {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        let client = self.client.clone();
+        HttpConnectorFuture::new(async move {
+            let request = request
+                .try_into_http1x()
+                .map_err(|e| ConnectorError::other(Box::new(e), None))?;
+            let (parts, body) = request.into_parts();
+            let response = client
+                .request(parts.method, parts.uri.to_string())
+                .headers(parts.headers)
+                .body(reqwest::Body::wrap(body))
+                .send()
+                .await
+                .map_err(|e| {
+                    if e.is_timeout() {
+                        ConnectorError::timeout(Box::new(e))
+                    } else if e.is_connect() {
+                        ConnectorError::io(Box::new(e))
+                    } else {
+                        ConnectorError::other(Box::new(e), None)
+                    }
+                })?;
+            let response = http::Response::from(response).map(SdkBody::from_body_1_x);
+            HttpResponse::try_from(response).map_err(|e| ConnectorError::other(Box::new(e), None))
+        })
+    }
+}
+
+// Build an S3 client pointed at R2 from the configured settings; called once during setup
+async fn build_r2_client(settings: &R2Settings, proxy: &ProxySettings) -> anyhow::Result<R2Client> {
+    let access_key_id = settings.access_key_id.as_deref().ok_or_else(|| anyhow!("R2_ACCESS_KEY_ID not set"))?;
+    let secret_access_key = settings.secret_access_key.as_deref().ok_or_else(|| anyhow!("R2_SECRET_ACCESS_KEY not set"))?;
+    let bucket_name = settings.bucket_name.clone().ok_or_else(|| anyhow!("R2_BUCKET_NAME not set"))?;
+
+    // Use the explicit S3 endpoint if there is one, otherwise construct the R2 endpoint URL
+    let custom_endpoint = settings.endpoint_url.as_deref();
+    let endpoint_url = match custom_endpoint {
+        Some(url) => url.to_string(),
+        None => {
+            let account_id = settings.account_id.as_deref().ok_or_else(|| anyhow!("R2_ACCOUNT_ID not set"))?;
+            format!("https://{}.r2.cloudflarestorage.com", account_id)
+        }
+    };
+    info!("Using S3 endpoint: {}", endpoint_url);
+
+    // Configure AWS SDK with optimized retry settings
+    let region_provider = RegionProviderChain::first_try(Region::new(settings.region.clone()));
+    let mut loader = aws_config::defaults(aws_config::BehaviorVersion::latest());
+    if proxy.is_configured() {
+        // The SDK's default hyper client ignores proxy env vars, so send R2 traffic through reqwest
+        info!("Routing R2 requests through the configured proxy");
+        loader = loader.http_client(ProxyHttpClient(proxy.clone()));
+    }
+    let shared_config = loader
+        .region(region_provider)
+        .endpoint_url(endpoint_url.clone()) // Clone endpoint_url for use here
+        .retry_config(aws_config::retry::RetryConfig::standard()
+            .with_max_attempts(3) // Limit retry attempts to reduce latency on failure
+            .with_initial_backoff(Duration::from_millis(100))) // Start retries quickly
+        .credentials_provider(aws_sdk_s3::config::Credentials::new(
+            access_key_id,
+            secret_access_key,
+            None, // session token
+            None, // expiry
+            "cloudflare-r2-provider", // provider name
+        ))
+        .load()
+        .await;
+
+    // Self-hosted stores like MinIO usually don't serve virtual-hosted bucket subdomains
+    let s3_config = aws_sdk_s3::config::Builder::from(&shared_config)
+        .force_path_style(custom_endpoint.is_some())
+        .build();
+
+    Ok(R2Client {
+        client: S3Client::from_conf(s3_config),
+        bucket_name,
+    })
+}
+
+// Generate a pre-signed GET URL for an existing object key
+// With `download_filename`, browsers download the object under that name instead of showing it inline
+async fn presign_get_url(
+    client: &S3Client,
+    bucket_name: &str,
+    key: &str,
+    expires_in: Duration,
+    download_filename: Option<&str>,
+) -> anyhow::Result<String> {
+    let presigning_config = PresigningConfig::builder()
+        .expires_in(expires_in)
+        .build()
+        .context("Failed to create presigning config")?;
+
+    debug!("Generating pre-signed URL for key: {}", key);
+    let presigned_request = client.get_object()
+        .bucket(bucket_name)
+        .key(key)
+        .set_response_content_disposition(download_filename.map(attachment_disposition))
+        .presigned(presigning_config)
+        .await
+        .context("Failed to generate pre-signed URL")?;
+
+    Ok(presigned_request.uri().to_string())
+}
+
+// `attachment` Content-Disposition with an ASCII fallback name plus the RFC 5987 UTF-8 form
+fn attachment_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    let encoded: String = filename
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
+
+// Payload for `upload_progress` events
+#[derive(Serialize, Clone)]
+struct UploadProgress {
+    key: String,
+    bytes_sent: u64,
+    total_bytes: u64,
+    percent: u64,
+}
+
+// Size of each chunk handed to the SDK; progress is reported per chunk
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+// What an upload sends: bytes already in memory, or a file streamed from disk so large
+// uploads never have to fit in memory
+enum UploadSource {
+    Bytes(bytes::Bytes),
+    File { path: std::path::PathBuf, len: u64 },
+}
+
+impl UploadSource {
+    fn len(&self) -> u64 {
+        match self {
+            UploadSource::Bytes(data) => data.len() as u64,
+            UploadSource::File { len, .. } => *len,
+        }
+    }
+
+    fn read_error(path: &Path, e: impl std::fmt::Display) -> AppError {
+        AppError::R2Upload(format!("Failed to read file '{}' for upload: {}", path.display(), e))
+    }
+
+    // The first few KB, enough for content-type sniffing
+    async fn head(&self) -> std::result::Result<bytes::Bytes, AppError> {
+        use tokio::io::AsyncReadExt;
+
+        match self {
+            UploadSource::Bytes(data) => Ok(data.clone()),
+            UploadSource::File { path, .. } => {
+                let file = tokio::fs::File::open(path).await.map_err(|e| Self::read_error(path, e))?;
+                let mut head = Vec::with_capacity(8192);
+                file.take(8192).read_to_end(&mut head).await.map_err(|e| Self::read_error(path, e))?;
+                Ok(head.into())
+            }
+        }
+    }
+
+    // Hex SHA-256 of the content; files are hashed a chunk at a time
+    async fn sha256_hex(&self) -> std::result::Result<String, AppError> {
+        use sha2::{Digest, Sha256};
+        use tokio::io::AsyncReadExt;
+
+        match self {
+            UploadSource::Bytes(data) => Ok(format!("{:x}", Sha256::digest(data))),
+            UploadSource::File { path, .. } => {
+                let mut file = tokio::fs::File::open(path).await.map_err(|e| Self::read_error(path, e))?;
+                let mut hasher = Sha256::new();
+                let mut chunk = vec![0; UPLOAD_CHUNK_SIZE];
+                loop {
+                    let read = file.read(&mut chunk).await.map_err(|e| Self::read_error(path, e))?;
+                    if read == 0 {
+                        break;
+                    }
+                    hasher.update(&chunk[..read]);
+                }
+                Ok(format!("{:x}", hasher.finalize()))
+            }
+        }
+    }
+
+    // `len` bytes starting at `offset`, e.g. one part of a multipart upload
+    async fn slice(&self, offset: u64, len: u64) -> std::result::Result<ByteStream, AppError> {
+        match self {
+            UploadSource::Bytes(data) => Ok(ByteStream::from(data.slice(offset as usize..(offset + len) as usize))),
+            UploadSource::File { path, .. } => ByteStream::read_from()
+                .path(path)
+                .offset(offset)
+                .length(aws_smithy_types::byte_stream::Length::Exact(len))
+                .buffer_size(UPLOAD_CHUNK_SIZE)
+                .build()
+                .await
+                .map_err(|e| Self::read_error(path, e)),
+        }
+    }
+
+    // The whole content as a request body that reports progress as the SDK pulls it.
+    // Retryable so the SDK can rebuild the body if it needs to resend.
+    async fn progress_body(
+        &self,
+        on_progress: Arc<dyn Fn(u64, u64) + Send + Sync>,
+    ) -> std::result::Result<ByteStream, AppError> {
+        let total = self.len();
+        let source = match self {
+            UploadSource::Bytes(data) => ProgressSource::Bytes { data: data.clone(), offset: 0 },
+            UploadSource::File { .. } => ProgressSource::Stream(self.slice(0, total).await?.into_inner()),
+        };
+        Ok(ByteStream::new(SdkBody::retryable(move || {
+            let source = match &source {
+                ProgressSource::Bytes { data, .. } => ProgressSource::Bytes { data: data.clone(), offset: 0 },
+                ProgressSource::Stream(body) => ProgressSource::Stream(body.try_clone().unwrap_or_else(SdkBody::taken)),
+            };
+            SdkBody::from_body_1_x(ProgressBody {
+                source,
+                sent: 0,
+                total,
+                on_progress: on_progress.clone(),
+            })
+        })))
+    }
+}
+
+enum ProgressSource {
+    // Handed out UPLOAD_CHUNK_SIZE bytes at a time
+    Bytes { data: bytes::Bytes, offset: usize },
+    // A file body from the SDK, which already reads in UPLOAD_CHUNK_SIZE buffers
+    Stream(SdkBody),
+}
+
+// Request body that yields the upload in chunks and reports how many bytes the SDK has pulled
+struct ProgressBody {
+    source: ProgressSource,
+    sent: u64,
+    total: u64,
+    on_progress: Arc<dyn Fn(u64, u64) + Send + Sync>,
+}
+
+impl http_body::Body for ProgressBody {
+    type Data = bytes::Bytes;
+    type Error = aws_smithy_types::body::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<std::result::Result<http_body::Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let frame = match &mut this.source {
+            ProgressSource::Bytes { data, offset } => {
+                if *offset >= data.len() {
+                    return Poll::Ready(None);
+                }
+                let end = (*offset + UPLOAD_CHUNK_SIZE).min(data.len());
+                let chunk = data.slice(*offset..end);
+                *offset = end;
+                http_body::Frame::data(chunk)
+            }
+            ProgressSource::Stream(body) => match http_body::Body::poll_frame(Pin::new(body), cx) {
+                Poll::Ready(Some(Ok(frame))) => frame,
+                other => return other,
+            },
+        };
+        if let Some(chunk) = frame.data_ref() {
+            this.sent += chunk.len() as u64;
+            (this.on_progress)(this.sent, this.total);
+        }
+        Poll::Ready(Some(Ok(frame)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match &self.source {
+            ProgressSource::Bytes { data, offset } => *offset >= data.len(),
+            ProgressSource::Stream(body) => http_body::Body::is_end_stream(body),
+        }
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        http_body::SizeHint::with_exact(self.total.saturating_sub(self.sent))
+    }
+}
+
+// --- R2 Upload Command ---
+#[tauri::command]
+// Modify the return type to use the UploadResult struct
+async fn upload_image_to_r2<R: Runtime>(
+    app: AppHandle<R>,
+    r2: State<'_, R2State>,
+    file_path: String,
+    expires_secs: Option<u64>,
+    window_label: Option<String>,
+    timeout_secs: Option<u64>,
+    download_filename: Option<String>,
+    verify: Option<bool>,
+    dedupe: Option<bool>,
+    prefix: Option<String>,
+) -> std::result::Result<UploadResult, AppError> {
+    info!("Attempting to upload image from path: {}", file_path);
+
+    let key_prefix = match prefix {
+        Some(prefix) => sanitize_key_prefix(&prefix).map_err(AppError::InvalidInput)?,
+        None => None,
+    };
+
+    let file_stem = Path::new(&file_path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("upload");
+    let extension = Path::new(&file_path)
+        .extension()
+        .and_then(|s| s.to_str())
+        .unwrap_or("png"); // Default to png if no extension
+
+    // Only the size is read up front; the content is streamed from disk during the upload
+    let metadata = tokio::fs::metadata(&file_path)
+        .await
+        .map_err(|e| AppError::R2Upload(format!("Failed to read file '{}' for upload: {}", file_path, e)))?;
+    let source = UploadSource::File { path: file_path.clone().into(), len: metadata.len() };
+
+    let options = UploadOptions {
+        expires_secs,
+        window_label: window_label.as_deref(),
+        timeout_secs,
+        download_filename: download_filename.as_deref(),
+        verify: verify.unwrap_or(false),
+        dedupe: dedupe.unwrap_or(false),
+        key_prefix: key_prefix.as_deref(),
+    };
+    upload_to_r2(&app, &r2, source, file_stem, extension, options).await
+}
+
+// Upload in-memory bytes, e.g. a capture that never touched disk
+#[tauri::command]
+async fn upload_bytes_to_r2<R: Runtime>(
+    app: AppHandle<R>,
+    r2: State<'_, R2State>,
+    bytes: Vec<u8>,
+    extension: String,
+    expires_secs: Option<u64>,
+    window_label: Option<String>,
+    timeout_secs: Option<u64>,
+) -> std::result::Result<UploadResult, AppError> {
+    let extension = extension.trim().trim_start_matches('.');
+    let extension = if extension.is_empty() { "png" } else { extension };
+    let options = UploadOptions {
+        expires_secs,
+        window_label: window_label.as_deref(),
+        timeout_secs,
+        ..Default::default()
+    };
+    upload_to_r2(&app, &r2, UploadSource::Bytes(bytes.into()), "upload", extension, options).await
+}
+
+// Files above the threshold go up in parts, so one stalled request doesn't restart the whole upload.
+// Parts must be at least 5 MB, except the last.
+const MULTIPART_THRESHOLD_BYTES: u64 = 8 * 1024 * 1024;
+const MULTIPART_PART_SIZE: usize = 5 * 1024 * 1024;
+
+// Upload `source` as a multipart upload, reading one part at a time and reporting progress
+// after each. The upload id stays in `pending_upload_id` until the upload completes, so the
+// caller can abort it on failure, cancellation or timeout and R2 doesn't keep the orphaned parts.
+async fn multipart_upload(
+    client: &S3Client,
+    bucket_name: &str,
+    key: &str,
+    content_type: &str,
+    source: &UploadSource,
+    on_progress: Arc<dyn Fn(u64, u64) + Send + Sync>,
+    pending_upload_id: &Mutex<Option<String>>,
+) -> std::result::Result<(), AppError> {
+    use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+
+    let created = client.create_multipart_upload()
+        .bucket(bucket_name)
+        .key(key)
+        .content_type(content_type)
+        .send()
+        .await
+        .map_err(|e| AppError::R2Upload(format!("Failed to start multipart upload: {:?}", e.into_service_error())))?;
+    let upload_id = created
+        .upload_id()
+        .ok_or_else(|| AppError::R2Upload("R2 did not return a multipart upload id".to_string()))?
+        .to_string();
+    if let Ok(mut pending) = pending_upload_id.lock() {
+        *pending = Some(upload_id.clone());
+    }
+
+    let total_bytes = source.len();
+    let mut parts = Vec::new();
+    for (index, offset) in (0..total_bytes).step_by(MULTIPART_PART_SIZE).enumerate() {
+        let end = (offset + MULTIPART_PART_SIZE as u64).min(total_bytes);
+        let part_number = index as i32 + 1;
+        let body = source.slice(offset, end - offset).await?;
+        let part = client.upload_part()
+            .bucket(bucket_name)
+            .key(key)
+            .upload_id(&upload_id)
+            .part_number(part_number)
+            .content_length((end - offset) as i64)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| {
+                AppError::R2Upload(format!("Failed to upload part {}: {:?}", part_number, e.into_service_error()))
+            })?;
+        parts.push(
+            CompletedPart::builder()
+                .part_number(part_number)
+                .set_e_tag(part.e_tag().map(str::to_string))
+                .build(),
+        );
+        on_progress(end, total_bytes);
+    }
+
+    client.complete_multipart_upload()
+        .bucket(bucket_name)
+        .key(key)
+        .upload_id(&upload_id)
+        .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+        .send()
+        .await
+        .map_err(|e| AppError::R2Upload(format!("Failed to complete multipart upload: {:?}", e.into_service_error())))?;
+    if let Ok(mut pending) = pending_upload_id.lock() {
+        *pending = None;
+    }
+    Ok(())
+}
+
+// Best-effort cleanup of an interrupted multipart upload; failures are only logged
+async fn abort_multipart_upload(client: &S3Client, bucket_name: &str, key: &str, upload_id: &str) {
+    match client.abort_multipart_upload()
+        .bucket(bucket_name)
+        .key(key)
+        .upload_id(upload_id)
+        .send()
+        .await
+    {
+        Ok(_) => info!("Aborted multipart upload {} of {}", upload_id, key),
+        Err(e) => warn!("Failed to abort multipart upload {}: {:?}", upload_id, e.into_service_error()),
+    }
+}
+
+// Fallback content type when the bytes aren't recognised
+fn content_type_for_extension(extension: &str) -> &'static str {
+    match extension.to_lowercase().as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+// Overall limit for one upload, overridable per call
+const DEFAULT_UPLOAD_TIMEOUT_SECS: u64 = 60;
+
+// Abort in-flight uploads by their R2 key (as reported in `upload_progress` events); every
+// upload currently writing that key is cancelled. Returns false when none was running.
+#[tauri::command]
+fn cancel_upload(key: String, uploads: State<'_, UploadCancellations>) -> std::result::Result<bool, String> {
+    let uploads = uploads.0.lock().map_err(|e| format!("Failed to lock upload registry: {}", e))?;
+    let mut cancelled = false;
+    for (_, token) in uploads.iter().filter(|(id, _)| upload_registration_key(id) == key) {
+        token.cancel();
+        cancelled = true;
+    }
+    Ok(cancelled)
+}
+
+// Per-upload knobs shared by the upload commands; all optional
+#[derive(Default)]
+struct UploadOptions<'a> {
+    expires_secs: Option<u64>,
+    // Window that receives `upload_progress` events
+    window_label: Option<&'a str>,
+    timeout_secs: Option<u64>,
+    // Presign as a download under this name rather than for inline display
+    download_filename: Option<&'a str>,
+    // Confirm the object is readable before presigning it
+    verify: bool,
+    // Key the object by its SHA-256 and skip the upload when it's already stored
+    dedupe: bool,
+    // Folder for the generated key, already run through sanitize_key_prefix
+    key_prefix: Option<&'a str>,
+    // Also send `capture_stage` uploading/presigning events to the progress window (captures only)
+    capture_stages: bool,
+}
+
+// Normalize a user-supplied key prefix into `folder/sub/` form: backslashes become
+// slashes, empty segments and the leading slash are dropped, and `.`/`..` segments are
+// rejected so a prefix can't climb out of its folder. An all-empty prefix yields None.
+fn sanitize_key_prefix(prefix: &str) -> std::result::Result<Option<String>, String> {
+    if prefix.chars().any(char::is_control) {
+        return Err("Key prefix must not contain control characters".to_string());
+    }
+    let normalized = prefix.replace('\\', "/");
+    let segments: Vec<&str> = normalized.split('/').map(str::trim).filter(|segment| !segment.is_empty()).collect();
+    if segments.iter().any(|segment| *segment == "." || *segment == "..") {
+        return Err(format!("Key prefix must not contain '.' or '..' segments, got '{}'", prefix));
+    }
+    if segments.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(format!("{}/", segments.join("/"))))
+}
+
+async fn r2_object_exists(client: &S3Client, bucket_name: &str, key: &str) -> std::result::Result<bool, AppError> {
+    match client.head_object().bucket(bucket_name).key(key).send().await {
+        Ok(_) => Ok(true),
+        Err(e) if e.as_service_error().map_or(false, |service_error| service_error.is_not_found()) => Ok(false),
+        Err(e) => Err(AppError::R2Upload(format!("Failed to check for existing object {}: {:?}", key, e.into_service_error()))),
+    }
+}
+
+// Delays between head_object checks when verifying a fresh upload
+const VERIFY_BACKOFF_MS: [u64; 4] = [100, 200, 400, 800];
+
+// Poll until a just-written object is visible, so the presigned URL doesn't briefly 404
+async fn wait_until_available(client: &S3Client, bucket_name: &str, key: &str) -> std::result::Result<(), AppError> {
+    for (attempt, delay_ms) in std::iter::once(0).chain(VERIFY_BACKOFF_MS).enumerate() {
+        tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+        match client.head_object().bucket(bucket_name).key(key).send().await {
+            Ok(_) => return Ok(()),
+            Err(e) if e.as_service_error().map_or(false, |service_error| service_error.is_not_found()) => {
+                debug!("{} not visible yet (check {})", key, attempt + 1);
+            }
+            Err(e) => {
+                return Err(AppError::R2Upload(format!(
+                    "Failed to verify upload of {}: {:?}",
+                    key,
+                    e.into_service_error()
+                )));
+            }
+        }
+    }
+    Err(AppError::R2Upload(format!("Uploaded {} but it is not readable yet", key)))
+}
+
+// Shared upload path: put `source` under `{file_stem}-{uuid}.{extension}` and presign it
+#[tracing::instrument(skip_all, fields(bytes = source.len(), key = tracing::field::Empty))]
+async fn upload_to_r2<R: Runtime>(
+    app: &AppHandle<R>,
+    r2: &R2State,
+    source: UploadSource,
+    file_stem: &str,
+    extension: &str,
+    options: UploadOptions<'_>,
+) -> std::result::Result<UploadResult, AppError> {
+    let UploadOptions {
+        expires_secs,
+        window_label,
+        timeout_secs,
+        download_filename,
+        verify,
+        dedupe,
+        key_prefix,
+        capture_stages,
+    } = options;
+    let R2Client { client, bucket_name } = r2.get()?;
+
+    // Generate a unique key (filename) for the R2 object; identical bytes share a key when deduping
+    let name = if dedupe {
+        format!("{}.{}", source.sha256_hex().await?, extension)
+    } else {
+        format!("{}-{}.{}", file_stem, Uuid::new_v4(), extension)
+    };
+    let key = format!("{}{}", key_prefix.unwrap_or_default(), name);
+    tracing::Span::current().record("key", key.as_str());
+    info!("Generated R2 key: {}", key);
+    let total_bytes = source.len();
+
+    // Trust the file's magic bytes over its extension, which may be wrong or missing
+    let content_type = infer::get(&source.head().await?)
+        .map(|kind| kind.mime_type())
+        .unwrap_or_else(|| content_type_for_extension(extension))
+        .to_string();
+
+    // Report progress to the requesting window, only when the whole-number percentage moves
+    let progress_window = window_label.and_then(|label| app.get_webview_window(label));
+    let stage_window = progress_window.clone().filter(|_| capture_stages);
+    let progress_key = key.clone();
+    let last_percent = AtomicU64::new(u64::MAX);
+    let on_progress: Arc<dyn Fn(u64, u64) + Send + Sync> = Arc::new(move |bytes_sent, total_bytes| {
+        let Some(window) = &progress_window else { return };
+        let percent = if total_bytes == 0 { 100 } else { bytes_sent * 100 / total_bytes };
+        if last_percent.swap(percent, Ordering::Relaxed) != percent {
+            let _ = window.emit("upload_progress", UploadProgress {
+                key: progress_key.clone(),
+                bytes_sent,
+                total_bytes,
+                percent,
+            });
+        }
+    });
+
+    // Register a cancellation token so cancel_upload can abort this upload by key
+    let cancel_token = CancellationToken::new();
+    let uploads = app.state::<UploadCancellations>();
+    let registration_id = upload_registration_id(&key);
+    uploads.0.lock()
+        .map_err(|e| AppError::Internal(format!("Failed to lock upload registry: {}", e)))?
+        .insert(registration_id.clone(), cancel_token.clone());
+    let _registration = TokenRegistration {
+        registry: uploads.0.clone(),
+        id: registration_id,
+    };
+
+    // Wait for an upload slot; the permit is released when this function returns.
+    // Queued uploads can still be cancelled, and the timeout only starts once we have a slot.
+    let limiter = app.state::<UploadLimiter>().0.clone();
+    let _permit = tokio::select! {
+        _ = cancel_token.cancelled() => {
+            return Err(AppError::Cancelled(format!("Upload of {} was cancelled", key)));
+        }
+        permit = limiter.acquire_owned() => permit
+            .map_err(|e| AppError::Internal(format!("Upload limiter closed: {}", e)))?,
+    };
+
+    // Bound the whole transfer, SDK retries included. A multipart upload that doesn't finish
+    // is aborted below, whether it failed, was cancelled or timed out.
+    let timeout = Duration::from_secs(timeout_secs.unwrap_or(DEFAULT_UPLOAD_TIMEOUT_SECS));
+    let pending_upload_id: Mutex<Option<String>> = Mutex::new(None);
+    let transfer = async {
+        info!("Uploading to bucket: {}", bucket_name);
+        if let Some(window) = &stage_window {
+            emit_capture_stage(window, CaptureStage::Uploading);
+        }
+        if dedupe && r2_object_exists(&client, &bucket_name, &key).await? {
+            info!("{} is already stored, skipping upload", key);
+            on_progress(total_bytes, total_bytes);
+        } else if total_bytes > MULTIPART_THRESHOLD_BYTES {
+            multipart_upload(&client, &bucket_name, &key, &content_type, &source, on_progress, &pending_upload_id).await?;
+        } else {
+            let body = source.progress_body(on_progress).await?;
+
+            // Upload to R2
+            let _put_object_output = client.put_object()
+                .bucket(&bucket_name)
+                .key(&key)
+                .content_length(total_bytes as i64)
+                .content_type(&content_type)
+                .body(body)
+                .send()
+                .await
+                .map_err(|e| {
+                     let sdk_error = e.into_service_error();
+                     let error_message = format!("Failed to upload to R2: {:?}", sdk_error);
+                     error!("{}", error_message);
+                     AppError::R2Upload(error_message)
+                })?;
+        }
+
+        info!("{} is in R2 bucket {}", key, bucket_name);
+        if verify {
+            wait_until_available(&client, &bucket_name, &key).await?;
+        }
+
+        // Generate pre-signed URL with optimized configuration
+        // Defaults to 30 minutes - balanced for security vs usability
+        if let Some(window) = &stage_window {
+            emit_capture_stage(window, CaptureStage::Presigning);
+        }
+        let presigned_url = presign_get_url(&client, &bucket_name, &key, presign_expiry(expires_secs), download_filename)
+            .await
+            .map_err(|e| AppError::R2Upload(format!("{:#}", e)))?;
+        debug!("Generated pre-signed URL: {}", presigned_url);
+        Ok::<String, AppError>(presigned_url)
+    };
+    let outcome = tokio::select! {
+        _ = cancel_token.cancelled() => Err(AppError::Cancelled(format!("Upload of {} was cancelled", key))),
+        result = tokio::time::timeout(timeout, transfer) => result.unwrap_or_else(|_| {
+            Err(AppError::Timeout(format!("Upload of {} timed out after {}s", key, timeout.as_secs())))
+        }),
+    };
+    if let Err(e) = &outcome {
+        let upload_id = pending_upload_id.lock().ok().and_then(|mut pending| pending.take());
+        if let Some(upload_id) = upload_id {
+            error!("{}", e);
+            abort_multipart_upload(&client, &bucket_name, &key, &upload_id).await;
+        }
+    }
+    let presigned_url = outcome?;
+
+    // Return both the key and the URL
+    Ok(UploadResult {
+        key,
+        url: presigned_url,
+        content_type: Some(content_type),
+        local_path: None,
+    })
+}
+// --- /R2 Upload Command ---
+
+// Default number of objects returned per list_r2_objects page; R2 caps a page at 1000
+const DEFAULT_LIST_LIMIT: i32 = 50;
+const MAX_LIST_LIMIT: i32 = 1000;
+
+// One page of list_r2_objects; pass `next_continuation_token` back to get the next page
+#[derive(Serialize)]
+struct ObjectPage {
+    items: Vec<UploadResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_continuation_token: Option<String>,
+}
+
+// List previously uploaded objects one page at a time, with fresh pre-signed URLs.
+// R2 pages in key order, so items are sorted newest first within each page only.
+#[tauri::command]
+async fn list_r2_objects(
+    r2: State<'_, R2State>,
+    prefix: Option<String>,
+    limit: Option<i32>,
+    continuation_token: Option<String>,
+) -> std::result::Result<ObjectPage, AppError> {
+    let R2Client { client, bucket_name } = r2.get()?;
+    let limit = limit.unwrap_or(DEFAULT_LIST_LIMIT).clamp(1, MAX_LIST_LIMIT);
+
+    let page = client.list_objects_v2()
+        .bucket(&bucket_name)
+        .set_prefix(prefix)
+        .max_keys(limit)
+        .set_continuation_token(continuation_token)
+        .send()
+        .await
+        .map_err(|e| AppError::Network(format!("Failed to list R2 objects: {:?}", e.into_service_error())))?;
+
+    let mut objects: Vec<(String, i64)> = page
+        .contents()
+        .iter()
+        .filter_map(|object| {
+            let modified = object.last_modified().map(|t| t.secs()).unwrap_or(0);
+            object.key().map(|key| (key.to_string(), modified))
+        })
+        .collect();
+    objects.sort_by(|a, b| b.1.cmp(&a.1));
+
+    let mut items = Vec::with_capacity(objects.len());
+    for (key, _) in objects {
+        let url = presign_get_url(&client, &bucket_name, &key, presign_expiry(None), None)
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to presign R2 object '{}': {:#}", key, e)))?;
+        items.push(UploadResult { key, url, content_type: None, local_path: None });
+    }
+
+    let next_continuation_token = if page.is_truncated().unwrap_or(false) {
+        page.next_continuation_token().map(str::to_string)
+    } else {
+        None
+    };
+    Ok(ObjectPage { items, next_continuation_token })
+}
+
+// Metadata of a stored R2 object
+#[derive(Serialize)]
+struct ObjectMeta {
+    key: String,
+    content_length: Option<i64>,
+    content_type: Option<String>,
+    // RFC 3339 timestamp
+    last_modified: Option<String>,
+}
+
+// Check an object still exists before presigning or displaying it
+#[tauri::command]
+async fn head_r2_object(r2: State<'_, R2State>, key: String) -> std::result::Result<ObjectMeta, AppError> {
+    let R2Client { client, bucket_name } = r2.get()?;
+
+    let output = client.head_object().bucket(&bucket_name).key(&key).send().await.map_err(|e| {
+        if e.as_service_error().map_or(false, |service_error| service_error.is_not_found()) {
+            AppError::NotFound(format!("R2 object '{}' does not exist", key))
+        } else {
+            AppError::Network(format!("Failed to read R2 object '{}': {:?}", key, e.into_service_error()))
+        }
+    })?;
+
+    Ok(ObjectMeta {
+        content_length: output.content_length(),
+        content_type: output.content_type().map(str::to_string),
+        last_modified: output
+            .last_modified()
+            .and_then(|at| at.fmt(aws_smithy_types::date_time::Format::DateTime).ok()),
+        key,
+    })
+}
+
+// Fetch an uploaded object back (e.g. to re-annotate a screenshot), reading the body chunk by chunk
+#[tauri::command]
+async fn download_r2_object(r2: State<'_, R2State>, key: String) -> std::result::Result<Vec<u8>, AppError> {
+    let R2Client { client, bucket_name } = r2.get()?;
+
+    let mut output = client.get_object().bucket(&bucket_name).key(&key).send().await.map_err(|e| {
+        if e.as_service_error().map_or(false, |service_error| service_error.is_no_such_key()) {
+            AppError::NotFound(format!("R2 object '{}' does not exist", key))
+        } else {
+            AppError::Network(format!("Failed to download R2 object '{}': {:?}", key, e.into_service_error()))
+        }
+    })?;
+
+    let mut data = Vec::with_capacity(output.content_length().unwrap_or(0).max(0) as usize);
+    while let Some(chunk) = output.body.next().await {
+        let chunk = chunk.map_err(|e| AppError::Network(format!("Failed to read R2 object '{}': {}", key, e)))?;
+        data.extend_from_slice(&chunk);
+    }
+    debug!("Downloaded {} bytes from {}", data.len(), key);
+    Ok(data)
+}
+
+// "Test connection" for settings: build a client from the configured settings and
+// list at most one key, so credentials and bucket are checked without uploading
+#[tauri::command]
+async fn test_r2_connection(config: State<'_, AppConfig>) -> std::result::Result<(), AppError> {
+    use aws_sdk_s3::error::{ProvideErrorMetadata, SdkError};
+
+    // The client only fails to build when settings are missing or malformed
+    let R2Client { client, bucket_name } = build_r2_client(&config.r2, &config.proxy)
+        .await
+        .map_err(|e| AppError::MissingEnvVar(format!("{:#}", e)))?;
+
+    match client.list_objects_v2().bucket(&bucket_name).max_keys(1).send().await {
+        Ok(_) => {
+            info!("R2 connection test succeeded for bucket {}", bucket_name);
+            Ok(())
+        }
+        Err(SdkError::DispatchFailure(e)) => Err(AppError::Network(format!("Could not reach R2 (network error): {:?}", e))),
+        Err(SdkError::TimeoutError(_)) => Err(AppError::Timeout("Timed out connecting to R2".to_string())),
+        Err(e) => {
+            let status = e.raw_response().map(|r| r.status().as_u16());
+            let code = e.code().unwrap_or_default().to_string();
+            let error = match (code.as_str(), status) {
+                ("NoSuchBucket", _) | (_, Some(404)) => {
+                    AppError::NotFound(format!("Bucket '{}' does not exist", bucket_name))
+                }
+                ("InvalidAccessKeyId" | "SignatureDoesNotMatch", _) | (_, Some(401)) => AppError::PermissionDenied(
+                    "R2 rejected the credentials; check R2_ACCESS_KEY_ID and R2_SECRET_ACCESS_KEY".to_string(),
+                ),
+                (_, Some(403)) => AppError::PermissionDenied(format!("Access to bucket '{}' was denied", bucket_name)),
+                _ => AppError::Api(format!("R2 connection test failed: {:?}", e.into_service_error())),
+            };
+            warn!("{}", error);
+            Err(error)
+        }
+    }
+}
+
+#[tauri::command]
+async fn delete_r2_object(r2: State<'_, R2State>, key: String) -> std::result::Result<(), AppError> {
+    let R2Client { client, bucket_name } = r2.get()?;
+
+    info!("Deleting R2 object: {}", key);
+    match client.delete_object().bucket(&bucket_name).key(&key).send().await {
+        Ok(_) => Ok(()),
+        // Already gone is as good as deleted
+        Err(e) if e.raw_response().map(|r| r.status().as_u16()) == Some(404) => {
+            info!("R2 object {} was already deleted", key);
+            Ok(())
+        }
+        Err(e) => {
+            let error_message = format!("Failed to delete R2 object '{}': {:?}", key, e.into_service_error());
+            error!("{}", error_message);
+            Err(AppError::Network(error_message))
+        }
+    }
+}
+
+// Mint a fresh pre-signed URL for an object uploaded earlier, so stored notes keep working
+#[tauri::command]
+async fn presign_r2_object(
+    r2: State<'_, R2State>,
+    key: String,
+    expires_secs: Option<u64>,
+    download_filename: Option<String>,
+) -> std::result::Result<String, AppError> {
+    let R2Client { client, bucket_name } = r2.get()?;
+    presign_get_url(&client, &bucket_name, &key, presign_expiry(expires_secs), download_filename.as_deref())
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to presign R2 object '{}': {:#}", key, e)))
+}
+
+#[tauri::command]
+async fn open_drag_window<R: Runtime>(app: AppHandle<R>) -> Result<()> {
+
+    // Check if the window already exists
+    if let Some(window) = app.get_webview_window("drag-chat") {
+        // If it exists, bring it to the front
+        window.set_focus()?;
+    } else {
+        // Restore the last saved geometry, falling back to the defaults
+        let geometry = load_window_geometry(&app, "drag-chat").await.unwrap_or(WindowGeometry {
+            x: 200.0,
+            y: 200.0,
+            width: 420.0,
+            height: 300.0,
+        });
+
+        // If it doesn't exist, create it
+        let builder = tauri::WebviewWindowBuilder::new(&app, "drag-chat", WebviewUrl::App("drag.html".into()))
+            .title("Drag Chat")
+            .inner_size(geometry.width, geometry.height)
+            .position(geometry.x, geometry.y)
+            .transparent(true) 
+            .decorations(false) // No window decorations (title bar, etc.)
+            .resizable(true)
+            .skip_taskbar(true)
+            .focused(true)
+            .shadow(false)
+            .always_on_top(true); // Let the user move it behind other windows
+
+        // Create the window and save its geometry as the user moves or resizes it
+        let window = builder.build()?;
+        track_window_geometry(&window);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+async fn close_drag_window<R: Runtime>(app: AppHandle<R>) -> Result<()> {
+    if let Some(window) = app.get_webview_window("drag-chat") {
+        window.close()?;
+    }
+    Ok(())
+}
+
+// Close the drag window if it's open, otherwise open it. Returns true when now open.
+#[tauri::command]
+async fn toggle_drag_window<R: Runtime>(app: AppHandle<R>) -> Result<bool> {
+    if let Some(window) = app.get_webview_window("drag-chat") {
+        window.close()?;
+        return Ok(false);
+    }
+    open_drag_window(app).await?;
+    Ok(true)
+}
+
+// Move the drag window, in logical pixels (e.g. to snap it to a screen edge)
+#[tauri::command]
+async fn move_drag_window<R: Runtime>(app: AppHandle<R>, x: f64, y: f64) -> Result<()> {
+    if let Some(window) = app.get_webview_window("drag-chat") {
+        window.set_position(tauri::LogicalPosition::new(x, y))?;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+async fn set_drag_window_size<R: Runtime>(app: AppHandle<R>, width: f64, height: f64) -> Result<()> {
+    if let Some(window) = app.get_webview_window("drag-chat") {
+        window.set_size(tauri::LogicalSize::new(width, height))?;
+    }
+    Ok(())
+}
+
+// Bounds for keyboard resizing of the drag window, in logical pixels
+const DRAG_WINDOW_MIN_SIZE: (f64, f64) = (280.0, 200.0);
+const DRAG_WINDOW_MAX_SIZE: (f64, f64) = (1600.0, 1200.0);
+
+// Resize the drag window by a logical delta, clamped to the bounds above.
+// set_size sets the inner size, so that's also what the delta is applied to.
+fn resize_drag_window<R: Runtime>(app: &AppHandle<R>, dw: f64, dh: f64) -> Result<()> {
+    if let Some(window) = app.get_webview_window("drag-chat") {
+        let size = window.inner_size()?.to_logical::<f64>(window.scale_factor()?);
+        let width = (size.width + dw).clamp(DRAG_WINDOW_MIN_SIZE.0, DRAG_WINDOW_MAX_SIZE.0);
+        let height = (size.height + dh).clamp(DRAG_WINDOW_MIN_SIZE.1, DRAG_WINDOW_MAX_SIZE.1);
+        window.set_size(tauri::LogicalSize::new(width, height))?;
+    }
+    Ok(())
+}
+
+// Keyboard-driven resizing, for hotkeys instead of dragging the window edge
+#[tauri::command]
+async fn grow_drag_window<R: Runtime>(app: AppHandle<R>, dw: f64, dh: f64) -> Result<()> {
+    resize_drag_window(&app, dw.abs(), dh.abs())
+}
+
+#[tauri::command]
+async fn shrink_drag_window<R: Runtime>(app: AppHandle<R>, dw: f64, dh: f64) -> Result<()> {
+    resize_drag_window(&app, -dw.abs(), -dh.abs())
+}
+
+// Let the floating chat drop behind other windows and pop back later
+#[tauri::command]
+async fn set_drag_window_always_on_top<R: Runtime>(app: AppHandle<R>, enabled: bool) -> Result<()> {
+    if let Some(window) = app.get_webview_window("drag-chat") {
+        window.set_always_on_top(enabled)?;
+    }
+    Ok(())
+}
+
+// --- Capture frame ---
+
+// Recording-friendly default for the capture frame
+const DEFAULT_CAPTURE_FRAME_ASPECT: f64 = 16.0 / 9.0;
+
+// Open a transparent, resizable frame whose height follows its width at a fixed aspect ratio.
+// capture_region_and_upload on this window grabs exactly what the frame surrounds.
+#[tauri::command]
+async fn open_capture_frame_window<R: Runtime>(app: AppHandle<R>, aspect: Option<f64>) -> std::result::Result<(), String> {
+    let aspect = aspect.unwrap_or(DEFAULT_CAPTURE_FRAME_ASPECT);
+    if !aspect.is_finite() || aspect <= 0.0 {
+        return Err(format!("Invalid aspect ratio {}: must be a positive number", aspect));
+    }
+
+    if let Some(window) = app.get_webview_window("capture-frame") {
+        return window.set_focus().map_err(|e| format!("Failed to focus capture frame: {}", e));
+    }
+
+    let width = 640.0;
+    let window = tauri::WebviewWindowBuilder::new(&app, "capture-frame", WebviewUrl::App("frame.html".into()))
+        .title("Capture Frame")
+        .inner_size(width, width / aspect)
+        .center()
+        .transparent(true)
+        .decorations(false)
+        .resizable(true)
+        .skip_taskbar(true)
+        .shadow(false)
+        .always_on_top(true)
+        .build()
+        .map_err(|e| format!("Failed to create capture frame: {}", e))?;
+
+    // Correct the height after every resize; the correction itself lands within tolerance and stops there
+    let frame = window.clone();
+    window.on_window_event(move |event| {
+        if let tauri::WindowEvent::Resized(size) = event {
+            let height = (size.width as f64 / aspect).round() as u32;
+            if size.width > 0 && size.height.abs_diff(height) > 1 {
+                if let Err(e) = frame.set_size(tauri::PhysicalSize::new(size.width, height)) {
+                    warn!("Failed to keep capture frame aspect ratio: {}", e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[tauri::command]
+async fn close_capture_frame_window<R: Runtime>(app: AppHandle<R>) -> Result<()> {
+    if let Some(window) = app.get_webview_window("capture-frame") {
+        window.close()?;
+    }
+    Ok(())
+}
+// --- /Capture frame ---
+
+// --- Notes DB ---
+// Same connection string the frontend and the SQL plugin preload use
+const NOTES_DB_URL: &str = "sqlite:notes.db";
+
+// Connection string of the notes database Rust-side queries use; switch_database changes it
+struct ActiveDatabase(std::sync::RwLock<String>);
+
+impl Default for ActiveDatabase {
+    fn default() -> Self {
+        ActiveDatabase(std::sync::RwLock::new(NOTES_DB_URL.to_string()))
+    }
+}
+
+fn active_database_url<R: Runtime>(app: &AppHandle<R>) -> String {
+    app.state::<ActiveDatabase>()
+        .0
+        .read()
+        .map(|url| url.clone())
+        .unwrap_or_else(|_| NOTES_DB_URL.to_string())
+}
+
+// Borrow the SQLite pool the SQL plugin opened for the active database (notes.db by default)
+async fn notes_db<R: Runtime>(app: &AppHandle<R>) -> std::result::Result<sqlx::SqlitePool, String> {
+    let url = active_database_url(app);
+    let instances = app.state::<DbInstances>();
+    let instances = instances.0.read().await;
+    match instances.get(&url) {
+        Some(DbPool::Sqlite(pool)) => Ok(pool.clone()),
+        _ => Err(format!("Database {} is not loaded", url)),
+    }
+}
+
+// Point notes, conversations and captures at `sqlite:{name}.db`, e.g. one per workspace.
+// New databases are created next to notes.db and migrated like it; the pool is registered
+// with the SQL plugin so the frontend can `Database.load` the returned URL as well.
+#[tauri::command]
+async fn switch_database<R: Runtime>(app: AppHandle<R>, name: String) -> std::result::Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() || name.len() > 64 || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!(
+            "Invalid database name '{}': use up to 64 letters, digits, '-' or '_'",
+            name
+        ));
+    }
+    let url = format!("sqlite:{}.db", name);
+
+    let instances = app.state::<DbInstances>();
+    let mut instances = instances.0.write().await;
+    if !instances.contains_key(&url) {
+        // The SQL plugin resolves `sqlite:` paths against the app config directory
+        let dir = app
+            .path()
+            .app_config_dir()
+            .map_err(|e| format!("Failed to resolve app config directory: {}", e))?;
+        std::fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        let options = sqlx::sqlite::SqliteConnectOptions::new()
+            .filename(dir.join(format!("{}.db", name)))
+            .create_if_missing(true);
+        let pool = sqlx::SqlitePool::connect_with(options)
+            .await
+            .map_err(|e| format!("Failed to open database {}: {}", url, e))?;
+
+        sqlx::migrate::Migrator::new(NotesMigrations(notes_migrations()))
+            .await
+            .map_err(|e| format!("Failed to load migrations: {}", e))?
+            .run(&pool)
+            .await
+            .map_err(|e| format!("Failed to migrate {}: {}", url, e))?;
+        instances.insert(url.clone(), DbPool::Sqlite(pool));
+    }
+    drop(instances);
+
+    *app.state::<ActiveDatabase>()
+        .0
+        .write()
+        .map_err(|e| format!("Failed to lock active database: {}", e))? = url.clone();
+    info!("Switched to database {}", url);
+    let _ = app.emit("database_switched", &url);
+    Ok(url)
+}
+
+#[tauri::command]
+async fn save_chat_message<R: Runtime>(
+    app: AppHandle<R>,
+    message: ChatMessage,
+    conversation_id: String,
+) -> std::result::Result<i64, String> {
+    let pool = notes_db(&app).await?;
+    let result = sqlx::query(
+        "INSERT INTO chat_messages (conversation_id, role, content, image_key) VALUES (?, ?, ?, ?)",
+    )
+    .bind(&conversation_id)
+    .bind(&message.role)
+    .bind(&message.content)
+    .bind(&message.image_key)
+    .execute(&pool)
+    .await
+    .map_err(|e| format!("Failed to save chat message: {}", e))?;
+
+    Ok(result.last_insert_rowid())
+}
+#[derive(Serialize)]
+struct Conversation {
+    id: String,
+    title: Option<String>,
+    system_prompt: Option<String>,
+    created_at: String,
+    updated_at: String,
+}
+
+#[tauri::command]
+async fn create_conversation<R: Runtime>(
+    app: AppHandle<R>,
+    title: String,
+    system_prompt: Option<String>,
+) -> std::result::Result<String, String> {
+    let pool = notes_db(&app).await?;
+    let id = Uuid::new_v4().to_string();
+    sqlx::query("INSERT INTO conversations (id, title, system_prompt) VALUES (?, ?, ?)")
+        .bind(&id)
+        .bind(&title)
+        .bind(system_prompt.filter(|s| !s.trim().is_empty()))
+        .execute(&pool)
+        .await
+        .map_err(|e| format!("Failed to create conversation: {}", e))?;
+    Ok(id)
+}
+
+#[tauri::command]
+async fn list_conversations<R: Runtime>(app: AppHandle<R>) -> std::result::Result<Vec<Conversation>, String> {
+    use sqlx::Row;
+
+    let pool = notes_db(&app).await?;
+    // Cast timestamps so they decode as plain strings
+    let rows = sqlx::query(
+        "SELECT id, title, system_prompt, CAST(created_at AS TEXT) AS created_at, CAST(updated_at AS TEXT) AS updated_at
+         FROM conversations ORDER BY updated_at DESC",
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| format!("Failed to list conversations: {}", e))?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(Conversation {
+                id: row.try_get("id")?,
+                title: row.try_get("title")?,
+                system_prompt: row.try_get("system_prompt")?,
+                created_at: row.try_get("created_at")?,
+                updated_at: row.try_get("updated_at")?,
+            })
+        })
+        .collect::<std::result::Result<Vec<_>, sqlx::Error>>()
+        .map_err(|e| format!("Failed to read conversation row: {}", e))
+}
+
+// Write a conversation to a Markdown file: a heading, then one `**role:** content` block per
+// message, with stored images linked through fresh presigned URLs
+#[tauri::command]
+async fn export_conversation_markdown<R: Runtime>(
+    app: AppHandle<R>,
+    r2: State<'_, R2State>,
+    conversation_id: String,
+    path: String,
+) -> std::result::Result<(), String> {
+    use sqlx::Row;
+
+    let pool = notes_db(&app).await?;
+    let title: Option<Option<String>> = sqlx::query_scalar("SELECT title FROM conversations WHERE id = ?")
+        .bind(&conversation_id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|e| format!("Failed to load conversation {}: {}", conversation_id, e))?;
+    let rows = sqlx::query(
+        "SELECT role, content, image_key FROM chat_messages
+         WHERE conversation_id = ? ORDER BY created_at, id",
+    )
+    .bind(&conversation_id)
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| format!("Failed to load messages for {}: {}", conversation_id, e))?;
+    if title.is_none() && rows.is_empty() {
+        return Err(format!("Conversation {} not found", conversation_id));
+    }
+
+    let title = title.flatten().filter(|t| !t.trim().is_empty()).unwrap_or_else(|| "Conversation".to_string());
+    let mut markdown = format!("# {}\n", title);
+    let r2_client = r2.get().ok();
+    for row in &rows {
+        let role: String = row.try_get("role").map_err(|e| format!("Failed to read message row: {}", e))?;
+        let content: String = row.try_get("content").map_err(|e| format!("Failed to read message row: {}", e))?;
+        let image_key: Option<String> = row.try_get("image_key").map_err(|e| format!("Failed to read message row: {}", e))?;
+
+        markdown.push_str(&format!("\n**{}:** {}\n", role, content));
+        if let Some(key) = image_key {
+            // Without R2 there's nothing to presign, so fall back to the bare key
+            let url = match &r2_client {
+                Some(R2Client { client, bucket_name }) => presign_get_url(client, bucket_name, &key, presign_expiry(None), None)
+                    .await
+                    .unwrap_or_else(|e| {
+                        warn!("Failed to presign {} for export: {:#}", key, e);
+                        key.clone()
+                    }),
+                None => key.clone(),
+            };
+            markdown.push_str(&format!("\n![{}]({})\n", key, url));
+        }
+    }
+
+    tokio::fs::write(&path, markdown)
+        .await
+        .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    info!("Exported conversation {} ({} messages) to {}", conversation_id, rows.len(), path);
+    Ok(())
+}
+
+// Set or clear (None / blank) the system prompt used for a conversation's chats
+#[tauri::command]
+async fn set_conversation_system_prompt<R: Runtime>(
+    app: AppHandle<R>,
+    id: String,
+    system_prompt: Option<String>,
+) -> std::result::Result<(), String> {
+    let pool = notes_db(&app).await?;
+    let result = sqlx::query("UPDATE conversations SET system_prompt = ? WHERE id = ?")
+        .bind(system_prompt.filter(|s| !s.trim().is_empty()))
+        .bind(&id)
+        .execute(&pool)
+        .await
+        .map_err(|e| format!("Failed to update conversation: {}", e))?;
+    if result.rows_affected() == 0 {
+        return Err(format!("Conversation {} not found", id));
+    }
+    Ok(())
+}
+
+// Stored system prompt for a conversation; None if it has none or doesn't exist
+async fn conversation_system_prompt<R: Runtime>(app: &AppHandle<R>, id: &str) -> std::result::Result<Option<String>, String> {
+    let pool = notes_db(app).await?;
+    let prompt: Option<Option<String>> = sqlx::query_scalar("SELECT system_prompt FROM conversations WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|e| format!("Failed to load conversation system prompt: {}", e))?;
+    Ok(prompt.flatten().filter(|s| !s.trim().is_empty()))
+}
+
+// A row of the notes table
+#[derive(Serialize)]
+struct Note {
+    id: i64,
+    title: String,
+    body: String,
+    // R2 key of the note's screenshot, if it has one
+    image_key: Option<String>,
+    created_at: String,
+    // Unset until the note is first edited
+    updated_at: Option<String>,
+}
+
+// Insert a note from Rust-side flows (e.g. saving OCR text) and return its row id
+#[tauri::command]
+async fn create_note<R: Runtime>(app: AppHandle<R>, title: String, body: String) -> std::result::Result<i64, String> {
+    let pool = notes_db(&app).await?;
+    let result = sqlx::query("INSERT INTO notes (title, body) VALUES (?, ?)")
+        .bind(&title)
+        .bind(&body)
+        .execute(&pool)
+        .await
+        .map_err(|e| format!("Failed to create note: {}", e))?;
+    Ok(result.last_insert_rowid())
+}
+
+// Turn free text into an FTS5 query: every word must match, as a prefix, and quoting
+// each word keeps punctuation from being parsed as query syntax
+fn fts_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Full-text search over note titles and bodies, best matches first
+#[tauri::command]
+async fn search_notes<R: Runtime>(app: AppHandle<R>, query: String) -> std::result::Result<Vec<Note>, String> {
+    use sqlx::Row;
+
+    let fts_query = fts_query(&query);
+    if fts_query.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let pool = notes_db(&app).await?;
+    let rows = sqlx::query(
+        "SELECT notes.id, notes.title, notes.body, notes.image_key, CAST(notes.created_at AS TEXT) AS created_at,
+                CAST(notes.updated_at AS TEXT) AS updated_at
+         FROM notes_fts JOIN notes ON notes.id = notes_fts.rowid
+         WHERE notes_fts MATCH ? ORDER BY notes_fts.rank",
+    )
+    .bind(&fts_query)
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| format!("Failed to search notes: {}", e))?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(Note {
+                id: row.try_get("id")?,
+                title: row.try_get("title")?,
+                body: row.try_get("body")?,
+                image_key: row.try_get("image_key")?,
+                created_at: row.try_get("created_at")?,
+                updated_at: row.try_get("updated_at")?,
+            })
+        })
+        .collect::<std::result::Result<Vec<_>, sqlx::Error>>()
+        .map_err(|e| format!("Failed to read note row: {}", e))
+}
+
+// Change a note's title and/or body; fields left as None keep their current value
+#[tauri::command]
+async fn update_note<R: Runtime>(
+    app: AppHandle<R>,
+    id: i64,
+    title: Option<String>,
+    body: Option<String>,
+) -> std::result::Result<(), String> {
+    let pool = notes_db(&app).await?;
+    let mut query = sqlx::QueryBuilder::<sqlx::Sqlite>::new("UPDATE notes SET updated_at = CURRENT_TIMESTAMP");
+    if let Some(title) = title {
+        query.push(", title = ").push_bind(title);
+    }
+    if let Some(body) = body {
+        query.push(", body = ").push_bind(body);
+    }
+    query.push(" WHERE id = ").push_bind(id);
+
+    let result = query
+        .build()
+        .execute(&pool)
+        .await
+        .map_err(|e| format!("Failed to update note {}: {}", id, e))?;
+    if result.rows_affected() == 0 {
+        return Err(format!("Note {} not found", id));
+    }
+    Ok(())
+}
+
+// Delete a note together with its screenshot. The R2 object goes first, and the row is
+// kept if that fails, so a note never points at an image we meant to remove but didn't.
+#[tauri::command]
+async fn delete_note<R: Runtime>(
+    app: AppHandle<R>,
+    r2: State<'_, R2State>,
+    id: i64,
+) -> std::result::Result<(), String> {
+    use sqlx::Row;
+
+    let pool = notes_db(&app).await?;
+    let row = sqlx::query("SELECT image_key FROM notes WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|e| format!("Failed to look up note {}: {}", id, e))?
+        .ok_or_else(|| format!("Note {} not found", id))?;
+    let image_key: Option<String> = row
+        .try_get("image_key")
+        .map_err(|e| format!("Failed to read note {}: {}", id, e))?;
+
+    // Local captures are removed from disk. Without R2 there's no bucket to clean up, so
+    // the row still goes rather than getting stuck behind an image that can't be deleted.
+    if let Some(key) = image_key {
+        if app.state::<AppConfig>().local_storage {
+            remove_local_capture(&app, &key);
+        } else if r2.0.is_none() {
+            warn!("R2 is not configured, leaving image {} of note {} in place", key, id);
+        } else {
+            delete_r2_object(r2, key)
+                .await
+                .map_err(|e| format!("Kept note {} because its image could not be deleted: {}", id, e))?;
+        }
+    }
+
+    sqlx::query("DELETE FROM notes WHERE id = ?")
+        .bind(id)
+        .execute(&pool)
+        .await
+        .map_err(|e| format!("Failed to delete note {}: {}", id, e))?;
+    Ok(())
+}
+
+// Best effort: a capture that's already gone, or can't be removed, shouldn't block deleting its note
+fn remove_local_capture<R: Runtime>(app: &AppHandle<R>, key: &str) {
+    // Keys are bare file names; anything else would point outside the captures folder
+    let Some(file_name) = Path::new(key).file_name().filter(|name| *name == std::ffi::OsStr::new(key)) else {
+        warn!("Not removing local capture with unexpected key {}", key);
+        return;
+    };
+    let path = match local_captures_dir(app) {
+        Ok(dir) => dir.join(file_name),
+        Err(e) => {
+            warn!("{}", e);
+            return;
+        }
+    };
+    match std::fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!("Failed to remove local capture {}: {}", path.display(), e),
+    }
+}
+
+// Development helper: delete every note and return how many rows went
+#[tauri::command]
+async fn clear_notes<R: Runtime>(app: AppHandle<R>) -> std::result::Result<u64, String> {
+    let pool = notes_db(&app).await?;
+    let result = sqlx::query("DELETE FROM notes")
+        .execute(&pool)
+        .await
+        .map_err(|e| format!("Failed to clear notes: {}", e))?;
+    Ok(result.rows_affected())
+}
+
+// Feeds the app's migrations to sqlx the same way the SQL plugin does at startup,
+// so the recorded checksums match and the plugin accepts the rebuilt database
+struct NotesMigrations(Vec<Migration>);
+
+impl<'s> sqlx::migrate::MigrationSource<'s> for NotesMigrations {
+    fn resolve(
+        self,
+    ) -> Pin<Box<dyn std::future::Future<Output = std::result::Result<Vec<sqlx::migrate::Migration>, sqlx::error::BoxDynError>> + Send + 's>> {
+        Box::pin(async move {
+            Ok(self.0
+                .into_iter()
+                .filter(|migration| matches!(migration.kind, MigrationKind::Up))
+                .map(|migration| {
+                    sqlx::migrate::Migration::new(
+                        migration.version,
+                        migration.description.into(),
+                        sqlx::migrate::MigrationType::ReversibleUp,
+                        migration.sql.into(),
+                        false,
+                    )
+                })
+                .collect())
+        })
+    }
+}
+
+// Drop every table in the active database and re-run the migrations. Only available in debug builds.
+#[tauri::command]
+async fn reset_database<R: Runtime>(app: AppHandle<R>) -> std::result::Result<(), String> {
+    use sqlx::Row;
+
+    if !cfg!(debug_assertions) {
+        return Err("reset_database is only available in debug builds".to_string());
+    }
+
+    let pool = notes_db(&app).await?;
+    // Virtual tables go first so their shadow tables are dropped along with them
+    let tables = sqlx::query(
+        "SELECT name FROM sqlite_master
+         WHERE type = 'table' AND name NOT LIKE 'sqlite_%'
+         ORDER BY CASE WHEN sql LIKE 'CREATE VIRTUAL%' THEN 0 ELSE 1 END",
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| format!("Failed to list tables: {}", e))?;
+
+    for row in &tables {
+        let name: String = row.try_get("name").map_err(|e| format!("Failed to read table name: {}", e))?;
+        sqlx::query(&format!("DROP TABLE IF EXISTS \"{}\"", name.replace('"', "\"\"")))
+            .execute(&pool)
+            .await
+            .map_err(|e| format!("Failed to drop table {}: {}", name, e))?;
+    }
+
+    sqlx::migrate::Migrator::new(NotesMigrations(notes_migrations()))
+        .await
+        .map_err(|e| format!("Failed to load migrations: {}", e))?
+        .run(&pool)
+        .await
+        .map_err(|e| format!("Failed to run migrations: {}", e))?;
+    Ok(())
+}
+// Saved geometry for a window, in logical pixels
+#[derive(Clone, Copy, Debug)]
+struct WindowGeometry {
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+}
+
+// Wait this long after the last move/resize before saving, so a drag doesn't write on every pixel
+const WINDOW_STATE_SAVE_DEBOUNCE: Duration = Duration::from_millis(300);
+
+async fn load_window_geometry<R: Runtime>(app: &AppHandle<R>, label: &str) -> Option<WindowGeometry> {
+    use sqlx::Row;
+
+    let pool = notes_db(app).await.ok()?;
+    let row = sqlx::query("SELECT x, y, width, height FROM window_state WHERE label = ?")
+        .bind(label)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|e| warn!("Failed to load window state for {}: {}", label, e))
+        .ok()??;
+
+    Some(WindowGeometry {
+        x: row.try_get("x").ok()?,
+        y: row.try_get("y").ok()?,
+        width: row.try_get("width").ok()?,
+        height: row.try_get("height").ok()?,
+    })
+}
+
+async fn save_window_geometry<R: Runtime>(app: &AppHandle<R>, label: &str, geometry: WindowGeometry) -> std::result::Result<(), String> {
+    let pool = notes_db(app).await?;
+    sqlx::query(
+        "INSERT INTO window_state (label, x, y, width, height, updated_at)
+         VALUES (?, ?, ?, ?, ?, CURRENT_TIMESTAMP)
+         ON CONFLICT(label) DO UPDATE SET
+             x = excluded.x, y = excluded.y, width = excluded.width, height = excluded.height,
+             updated_at = CURRENT_TIMESTAMP",
+    )
+    .bind(label)
+    .bind(geometry.x)
+    .bind(geometry.y)
+    .bind(geometry.width)
+    .bind(geometry.height)
+    .execute(&pool)
+    .await
+    .map_err(|e| format!("Failed to save window state for {}: {}", label, e))?;
+    Ok(())
+}
+
+fn current_window_geometry<R: Runtime>(window: &tauri::WebviewWindow<R>) -> Result<WindowGeometry> {
+    let scale = window.scale_factor()?;
+    let position = window.outer_position()?.to_logical::<f64>(scale);
+    let size = window.inner_size()?.to_logical::<f64>(scale);
+    Ok(WindowGeometry {
+        x: position.x,
+        y: position.y,
+        width: size.width,
+        height: size.height,
+    })
+}
+
+// Persist a window's geometry whenever it settles after a move or resize
+fn track_window_geometry<R: Runtime>(window: &tauri::WebviewWindow<R>) {
+    let generation = Arc::new(AtomicU64::new(0));
+    let tracked = window.clone();
+    window.on_window_event(move |event| {
+        if !matches!(event, tauri::WindowEvent::Moved(_) | tauri::WindowEvent::Resized(_)) {
+            return;
+        }
+        // Only the last event in a burst gets to save
+        let current = generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let generation = generation.clone();
+        let window = tracked.clone();
+        tauri::async_runtime::spawn(async move {
+            tokio::time::sleep(WINDOW_STATE_SAVE_DEBOUNCE).await;
+            if generation.load(Ordering::SeqCst) != current {
+                return;
+            }
+            match current_window_geometry(&window) {
+                Ok(geometry) => {
+                    if let Err(e) = save_window_geometry(window.app_handle(), window.label(), geometry).await {
+                        warn!("{}", e);
+                    }
+                }
+                Err(e) => warn!("Failed to read geometry of {}: {}", window.label(), e),
+            }
+        });
+    });
+}
+// A row of the captures table, with a freshly presigned URL for uploaded captures
+#[derive(Serialize)]
+struct CaptureRecord {
+    id: i64,
+    key: String,
+    url: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    created_at: String,
+}
+
+const DEFAULT_CAPTURE_HISTORY_LIMIT: u32 = 50;
+
+async fn record_capture<R: Runtime>(
+    app: &AppHandle<R>,
+    result: &UploadResult,
+    dimensions: Option<(u32, u32)>,
+) -> std::result::Result<(), String> {
+    let pool = notes_db(app).await?;
+    sqlx::query("INSERT INTO captures (key, url, width, height) VALUES (?, ?, ?, ?)")
+        .bind(&result.key)
+        .bind(&result.url)
+        .bind(dimensions.map(|(width, _)| width))
+        .bind(dimensions.map(|(_, height)| height))
+        .execute(&pool)
+        .await
+        .map_err(|e| format!("Failed to record capture {}: {}", result.key, e))?;
+    Ok(())
+}
+
+// Recent captures, newest first. Stored presigned URLs expire, so R2 captures get a new one;
+// local (file://) captures keep theirs.
+#[tauri::command]
+async fn list_captures<R: Runtime>(
+    app: AppHandle<R>,
+    r2: State<'_, R2State>,
+    limit: Option<u32>,
+) -> std::result::Result<Vec<CaptureRecord>, String> {
+    use sqlx::Row;
+
+    let pool = notes_db(&app).await?;
+    let rows = sqlx::query(
+        "SELECT id, key, url, width, height, CAST(created_at AS TEXT) AS created_at
+         FROM captures ORDER BY created_at DESC, id DESC LIMIT ?",
+    )
+    .bind(limit.unwrap_or(DEFAULT_CAPTURE_HISTORY_LIMIT))
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| format!("Failed to list captures: {}", e))?;
+
+    let r2_client = r2.get().ok();
+    let mut records = Vec::with_capacity(rows.len());
+    for row in rows {
+        let mut record = CaptureRecord {
+            id: row.try_get("id").map_err(|e| format!("Failed to read capture row: {}", e))?,
+            key: row.try_get("key").map_err(|e| format!("Failed to read capture row: {}", e))?,
+            url: row.try_get("url").map_err(|e| format!("Failed to read capture row: {}", e))?,
+            width: row.try_get("width").map_err(|e| format!("Failed to read capture row: {}", e))?,
+            height: row.try_get("height").map_err(|e| format!("Failed to read capture row: {}", e))?,
+            created_at: row.try_get("created_at").map_err(|e| format!("Failed to read capture row: {}", e))?,
+        };
+        if let (Some(R2Client { client, bucket_name }), false) = (&r2_client, record.url.starts_with("file://")) {
+            match presign_get_url(client, bucket_name, &record.key, presign_expiry(None), None).await {
+                Ok(url) => record.url = url,
+                Err(e) => warn!("Failed to presign capture {}: {:#}", record.key, e),
+            }
+        }
+        records.push(record);
+    }
+    Ok(records)
+}
+// --- /Notes DB ---
+
+// First window whose title contains any of `needles`. Matching is on partial titles
+// since apps (Tauri included) often add their name to the title.
+fn find_xcap_window_by_title(
+    xcap_windows: &[xcap::Window],
+    needles: &[&str],
+) -> std::result::Result<Option<xcap::Window>, String> {
+    for xcap_window in xcap_windows {
+        let title = xcap_window.title().map_err(|e| format!("Failed to get xcap window title: {}", e))?;
+        debug!("Found window: {}", title);
+
+        if needles.iter().any(|needle| !needle.is_empty() && title.contains(needle)) {
+            debug!("Found matching window: {}", title);
+            return Ok(Some(xcap_window.clone()));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(not(target_os = "macos"))]
+fn capture_region_xcap(window: &Window) -> std::result::Result<image::RgbaImage, String> {
+    use xcap::Window as XcapWindow;
+
+    // Get window title to find the corresponding xcap window
+    let window_title = window.title().map_err(|e| format!("Failed to get window title: {}", e))?;
+    let window_id = window.label();
+
+    debug!("Looking for window with label: {} and title: {}", window_id, window_title);
+
+    // Find all windows
+    let xcap_windows = XcapWindow::all().map_err(|e| format!("Failed to get window list: {}", e))?;
+    
+    // Try to find our window by title
+    let mut found_window = find_xcap_window_by_title(&xcap_windows, &[&window_title, window_id])?;
+
+    // If we can't find by title, use window dimensions as fallback
+    if found_window.is_none() {
+        debug!("Couldn't find window by title, falling back to position and size matching");
+        
+        // Get window geometry in physical pixels
+        let position = window.outer_position().map_err(|e| format!("Failed to get window position: {}", e))?;
+        let size = window.outer_size().map_err(|e| format!("Failed to get window size: {}", e))?;
+        let scale_factor = window.scale_factor().map_err(|e| format!("Failed to get scale factor: {}", e))?;
+        
+        // Convert from logical to physical pixels
+        let x = (position.x as f64 * scale_factor) as i32;
+        let y = (position.y as f64 * scale_factor) as i32;
+        let w = (size.width as f64 * scale_factor) as u32;
+        let h = (size.height as f64 * scale_factor) as u32;
+        
+        debug!("Looking for window at ({}, {}) with size {}x{}", x, y, w, h);
+        
+        // Find window with closest matching position and size
+        for xcap_window in &xcap_windows {
+            if xcap_window.is_minimized().map_err(|e| format!("Failed to check if window is minimized: {}", e))? {
+                continue;
+            }
+            
+            let wx = xcap_window.x().map_err(|e| format!("Failed to get xcap window x: {}", e))?;
+            let wy = xcap_window.y().map_err(|e| format!("Failed to get xcap window y: {}", e))?;
+            let ww = xcap_window.width().map_err(|e| format!("Failed to get xcap window width: {}", e))?;
+            let wh = xcap_window.height().map_err(|e| format!("Failed to get xcap window height: {}", e))?;
+            
+            // Check if positions are close (within 20 pixels)
+            let position_close = (wx - x).abs() < 20 && (wy - y).abs() < 20;
+            // Check if sizes are close (within 20 pixels)
+            let size_close = ((ww as i32) - (w as i32)).abs() < 20 && ((wh as i32) - (h as i32)).abs() < 20;
+            
+            if position_close && size_close {
+                let title = xcap_window.title().unwrap_or_else(|_| "Unknown".to_string());
+                debug!("Found window by position/size: {}", title);
+                found_window = Some(xcap_window.clone());
+                break;
+            }
+        }
+    }
+
+    // Capture the window if found
+    emit_capture_stage(window, CaptureStage::Capturing);
+    let full_img = if let Some(xcap_window) = found_window {
+        xcap_window.capture_image().map_err(|e| format!("Failed to capture window image: {}", e))?
+    } else {
+        // Fallback to original method if window can't be found
+        info!("Falling back to screen region capture");
+        
+        // Get window geometry in physical pixels
+        let position = window.outer_position().map_err(|e| format!("Failed to get window position: {}", e))?;
+        let size = window.outer_size().map_err(|e| format!("Failed to get window size: {}", e))?;
+        let scale_factor = window.scale_factor().map_err(|e| format!("Failed to get scale factor: {}", e))?;
+        
+        // Convert from logical to physical pixels
+        let x = (position.x as f64 * scale_factor) as i32;
+        let y = (position.y as f64 * scale_factor) as i32;
+        let w = (size.width as f64 * scale_factor) as u32;
+        let h = (size.height as f64 * scale_factor) as u32;
+        
+        // Use the original monitor-based capture as fallback
+        use xcap::Monitor;
+        let monitor = Monitor::from_point(x, y).map_err(|e| format!("Failed to get monitor at point ({}, {}): {}", x, y, e))?;
+        let monitor_img = monitor.capture_image().map_err(|e| format!("Failed to capture monitor image: {}", e))?;
+        
+        // Crop to the rectangle under our window
+        image::imageops::crop_imm(&monitor_img, x as u32, y as u32, w, h).to_image()
+    };
+
+    Ok(full_img)
+}
+
+// Output encodings for captures. Quality only applies to JPEG; image's WebP encoder is lossless.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CaptureFormat {
+    Png,
+    Jpeg,
+    WebP,
+}
+
+impl CaptureFormat {
+    fn parse(format: Option<&str>) -> std::result::Result<Self, String> {
+        match format.map(|f| f.trim().to_lowercase()).as_deref() {
+            None | Some("png") => Ok(Self::Png),
+            Some("jpeg") | Some("jpg") => Ok(Self::Jpeg),
+            Some("webp") => Ok(Self::WebP),
+            Some(other) => Err(format!("Unsupported capture format '{}'. Expected png, jpeg or webp", other)),
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Jpeg => "jpeg",
+            Self::WebP => "webp",
+        }
+    }
+}
+
+const DEFAULT_JPEG_QUALITY: u8 = 85;
+
+// Encode a captured image in the requested format
+fn encode_capture(img: &image::RgbaImage, format: CaptureFormat, quality: u8) -> std::result::Result<Vec<u8>, String> {
+    use std::io::Cursor;
+
+    let mut bytes = Vec::new();
+    let mut cursor = Cursor::new(&mut bytes);
+    match format {
+        CaptureFormat::Png => img.write_to(&mut cursor, image::ImageFormat::Png),
+        CaptureFormat::WebP => img.write_to(&mut cursor, image::ImageFormat::WebP),
+        CaptureFormat::Jpeg => {
+            // JPEG has no alpha channel
+            let rgb = image::DynamicImage::ImageRgba8(img.clone()).to_rgb8();
+            image::codecs::jpeg::JpegEncoder::new_with_quality(&mut cursor, quality.clamp(1, 100))
+                .encode_image(&rgb)
+        }
+    }
+    .map_err(|e| format!("Failed to encode image as {}: {}", format.extension(), e))?;
+
+    Ok(bytes)
+}
+
+// Downscale so neither side exceeds `max_dimension`, keeping the aspect ratio.
+// Images already within the bound are returned unchanged.
+fn fit_within(img: image::RgbaImage, max_dimension: Option<u32>) -> image::RgbaImage {
+    let Some(max_dimension) = max_dimension.filter(|&max| max > 0) else {
+        return img;
+    };
+    let (width, height) = img.dimensions();
+    if width <= max_dimension && height <= max_dimension {
+        return img;
+    }
+    let scale = max_dimension as f64 / width.max(height) as f64;
+    let new_width = ((width as f64 * scale).round() as u32).max(1);
+    let new_height = ((height as f64 * scale).round() as u32).max(1);
+    image::imageops::resize(&img, new_width, new_height, image::imageops::FilterType::Lanczos3)
+}
+
+// Read and remove the PNG screencapture wrote. It is always full size, so re-encode
+// when another format or a size bound was requested.
+#[cfg(target_os = "macos")]
+fn read_capture_file(
+    path: std::path::PathBuf,
+    format: CaptureFormat,
+    quality: u8,
+    max_dimension: Option<u32>,
+) -> std::result::Result<Vec<u8>, String> {
+    let bytes = if format == CaptureFormat::Png && max_dimension.is_none() {
+        std::fs::read(&path).map_err(|e| format!("Failed to read captured image: {}", e))
+    } else {
+        image::open(&path)
+            .map_err(|e| format!("Failed to read captured image: {}", e))
+            .and_then(|img| encode_capture(&fit_within(img.to_rgba8(), max_dimension), format, quality))
+    };
+    if let Err(e) = std::fs::remove_file(&path) {
+        warn!("Failed to remove temporary file {}: {}", path.display(), e);
+    }
+    bytes
+}
+
+// Capture an arbitrary screen rectangle given in logical pixels
+#[cfg(not(target_os = "macos"))]
+fn capture_screen_rect_xcap(x: f64, y: f64, width: f64, height: f64) -> std::result::Result<image::RgbaImage, String> {
+    use xcap::Monitor;
+
+    // Monitor bounds are physical, so the scale comes from the monitor that holds the point
+    // once scaled by its own factor
+    let monitors = Monitor::all().map_err(|e| format!("Failed to list monitors: {}", e))?;
+    let scale = monitors
+        .iter()
+        .find_map(|monitor| {
+            let scale = monitor.scale_factor().ok()? as f64;
+            let (px, py) = ((x * scale).round() as i32, (y * scale).round() as i32);
+            let (mx, my) = (monitor.x().ok()?, monitor.y().ok()?);
+            let (mw, mh) = (monitor.width().ok()? as i32, monitor.height().ok()? as i32);
+            (px >= mx && px < mx + mw && py >= my && py < my + mh).then_some(scale)
+        })
+        .unwrap_or(1.0);
+
+    // Convert to physical pixels once and use them for both the lookup and the crop
+    let physical_x = (x * scale).round() as i32;
+    let physical_y = (y * scale).round() as i32;
+    let monitor = Monitor::from_point(physical_x, physical_y)
+        .map_err(|e| format!("Failed to get monitor at point ({}, {}): {}", physical_x, physical_y, e))?;
+    let monitor_x = monitor.x().map_err(|e| format!("Failed to get monitor x: {}", e))?;
+    let monitor_y = monitor.y().map_err(|e| format!("Failed to get monitor y: {}", e))?;
+    let monitor_img = monitor.capture_image().map_err(|e| format!("Failed to capture monitor image: {}", e))?;
+
+    // Crop relative to the monitor's origin; crop_imm clamps to the image bounds
+    let crop_x = (physical_x - monitor_x).max(0) as u32;
+    let crop_y = (physical_y - monitor_y).max(0) as u32;
+    let crop_w = (width * scale).round() as u32;
+    let crop_h = (height * scale).round() as u32;
+
+    Ok(image::imageops::crop_imm(&monitor_img, crop_x, crop_y, crop_w, crop_h).to_image())
+}
+
+// Captures that have to touch disk go in `<app cache>/captures` rather than the shared temp dir,
+// so anything a crash leaves behind is ours to clean up
+fn capture_cache_dir<R: Runtime>(app: &AppHandle<R>) -> std::result::Result<std::path::PathBuf, String> {
+    let dir = app
+        .path()
+        .app_cache_dir()
+        .map_err(|e| format!("Failed to resolve app cache directory: {}", e))?
+        .join("captures");
+    std::fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    Ok(dir)
+}
+
+// A fresh `{prefix}-{uuid}.{extension}` path in the capture cache
+fn capture_cache_path<R: Runtime>(
+    app: &AppHandle<R>,
+    prefix: &str,
+    extension: &str,
+) -> std::result::Result<std::path::PathBuf, String> {
+    Ok(capture_cache_dir(app)?.join(format!("{}-{}.{}", prefix, Uuid::new_v4(), extension)))
+}
+
+// Files older than this are removed from the capture cache at startup
+const CAPTURE_CACHE_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+// Delete capture cache files older than `max_age_secs`, returning how many were removed
+#[tauri::command]
+fn cleanup_capture_cache<R: Runtime>(app: AppHandle<R>, max_age_secs: u64) -> std::result::Result<u64, String> {
+    let dir = capture_cache_dir(&app)?;
+    let max_age = Duration::from_secs(max_age_secs);
+    let mut removed = 0;
+    for entry in std::fs::read_dir(&dir).map_err(|e| format!("Failed to read {}: {}", dir.display(), e))? {
+        let Ok(entry) = entry else { continue };
+        let Ok(metadata) = entry.metadata() else { continue };
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .unwrap_or_default();
+        if metadata.is_file() && age >= max_age {
+            match std::fs::remove_file(entry.path()) {
+                Ok(()) => removed += 1,
+                Err(e) => warn!("Failed to remove {}: {}", entry.path().display(), e),
+            }
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(target_os = "macos")]
+#[link(name = "CoreGraphics", kind = "framework")]
+extern "C" {
+    fn CGPreflightScreenCaptureAccess() -> bool;
+    fn CGRequestScreenCaptureAccess() -> bool;
+    fn CGWindowListCopyWindowInfo(option: u32, relative_to_window: u32) -> core_foundation::array::CFArrayRef;
+    fn CGRectMakeWithDictionaryRepresentation(
+        dict: core_foundation::dictionary::CFDictionaryRef,
+        rect: *mut core_graphics::geometry::CGRect,
+    ) -> bool;
+}
+
+const SCREEN_CAPTURE_PERMISSION_DENIED: &str =
+    "Screen recording permission is not granted. Enable Eye in System Settings > Privacy & Security > Screen Recording.";
+
+// Other platforms have no screen-recording permission gate
+fn screen_capture_allowed() -> bool {
+    #[cfg(target_os = "macos")]
+    let allowed = unsafe { CGPreflightScreenCaptureAccess() };
+    #[cfg(not(target_os = "macos"))]
+    let allowed = true;
+    allowed
+}
+
+// Without permission macOS quietly produces wallpaper-only images, so capture commands check first
+fn ensure_screen_capture_permission() -> std::result::Result<(), AppError> {
+    if screen_capture_allowed() {
+        Ok(())
+    } else {
+        Err(AppError::PermissionDenied(SCREEN_CAPTURE_PERMISSION_DENIED.to_string()))
+    }
+}
+
+// macOS only reports whether access is currently granted, not whether the user was ever
+// asked, so request_screen_capture_permission leaves this marker behind when it prompts
+const SCREEN_CAPTURE_REQUESTED_MARKER: &str = "screen-capture-requested";
+
+fn screen_capture_marker_path<R: Runtime>(app: &AppHandle<R>) -> std::result::Result<std::path::PathBuf, String> {
+    app.path()
+        .app_config_dir()
+        .map(|dir| dir.join(SCREEN_CAPTURE_REQUESTED_MARKER))
+        .map_err(|e| format!("Failed to resolve app config directory: {}", e))
+}
+
+// Returns "granted", "denied", or "not-determined" when access is missing and Eye has never asked
+#[tauri::command]
+fn check_screen_capture_permission<R: Runtime>(app: AppHandle<R>) -> std::result::Result<String, String> {
+    if screen_capture_allowed() {
+        return Ok("granted".to_string());
+    }
+    let requested = screen_capture_marker_path(&app)?.exists();
+    Ok(if requested { "denied" } else { "not-determined" }.to_string())
+}
+
+// Show the system prompt (macOS only shows it once; later calls just report the current state)
+// and remember that it was shown. Returns whether access is granted.
+#[tauri::command]
+fn request_screen_capture_permission<R: Runtime>(app: AppHandle<R>) -> std::result::Result<bool, String> {
+    if screen_capture_allowed() {
+        return Ok(true);
+    }
+    let marker = screen_capture_marker_path(&app)?;
+    if let Some(dir) = marker.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    std::fs::write(&marker, b"").map_err(|e| format!("Failed to write {}: {}", marker.display(), e))?;
+
+    #[cfg(target_os = "macos")]
+    let granted = unsafe { CGRequestScreenCaptureAccess() };
+    #[cfg(not(target_os = "macos"))]
+    let granted = true;
+    Ok(granted)
+}
+
+// Run `screencapture` over a rectangle in points (top-left origin) into a temporary PNG
+#[cfg(target_os = "macos")]
+fn screencapture_rect<R: Runtime>(
+    app: &AppHandle<R>,
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+    include_shadow: bool,
+) -> std::result::Result<std::path::PathBuf, String> {
+    screencapture_to_temp(app, &["-R", &format!("{},{},{},{}", x, y, width, height)], include_shadow)
+}
+
+// Run `screencapture` with the given selection arguments into a temporary PNG.
+// The drop shadow is omitted unless `include_shadow` is set.
+#[cfg(target_os = "macos")]
+fn screencapture_to_temp<R: Runtime>(
+    app: &AppHandle<R>,
+    selection: &[&str],
+    include_shadow: bool,
+) -> std::result::Result<std::path::PathBuf, String> {
+    use std::process::Command;
+
+    let dest = capture_cache_path(app, "region", "png")?;
+    let dest_path = dest.to_string_lossy().to_string();
+
+    // Let Core Graphics write directly to the file
+    let mut command = Command::new("screencapture");
+    command.arg("-x"); // No sound
+    if !include_shadow {
+        command.arg("-o"); // No shadow
+    }
+    let output = command
+        .args(selection)
+        .arg(&dest_path)
+        .output()
+        .map_err(|e| format!("Failed to capture: {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!("screencapture command failed: {}",
+            String::from_utf8_lossy(&output.stderr)));
+    }
+    Ok(dest)
+}
+
+// Where captures live in local storage mode; their file names double as object keys
+fn local_captures_dir<R: Runtime>(app: &AppHandle<R>) -> std::result::Result<std::path::PathBuf, AppError> {
+    app.path()
+        .app_data_dir()
+        .map(|dir| dir.join("captures"))
+        .map_err(|e| AppError::Internal(format!("Failed to resolve app data directory: {}", e)))
+}
+
+// Write a capture into `<app_data>/captures`, returning its file name and path
+fn write_capture_to_app_data<R: Runtime>(
+    app: &AppHandle<R>,
+    bytes: &[u8],
+    extension: &str,
+) -> std::result::Result<(String, std::path::PathBuf), AppError> {
+    let dir = local_captures_dir(app)?;
+    std::fs::create_dir_all(&dir)
+        .map_err(|e| AppError::Internal(format!("Failed to create {}: {}", dir.display(), e)))?;
+
+    let key = format!("region-capture-{}.{}", Uuid::new_v4(), extension);
+    let path = dir.join(&key);
+    std::fs::write(&path, bytes)
+        .map_err(|e| AppError::Internal(format!("Failed to save capture to {}: {}", path.display(), e)))?;
+    Ok((key, path))
+}
+
+// Save a capture under `<app data>/captures` and describe it with a file:// URL
+fn save_capture_locally<R: Runtime>(
+    app: &AppHandle<R>,
+    bytes: &[u8],
+    extension: &str,
+) -> std::result::Result<UploadResult, AppError> {
+    let (key, path) = write_capture_to_app_data(app, bytes, extension)?;
+    let url = reqwest::Url::from_file_path(&path)
+        .map_err(|_| AppError::Internal(format!("Failed to build file URL for {}", path.display())))?;
+
+    Ok(UploadResult {
+        key,
+        url: url.to_string(),
+        content_type: Some(content_type_for_extension(extension).to_string()),
+        local_path: Some(path.to_string_lossy().into_owned()),
+    })
+}
+
+// Upload encoded capture bytes to R2, reporting progress to the capturing window.
+// In local storage mode the capture is saved to disk instead.
+async fn upload_capture_bytes(window: &Window, bytes: Vec<u8>, extension: &str) -> std::result::Result<UploadResult, AppError> {
+    if window.state::<AppConfig>().local_storage {
+        return save_capture_locally(window.app_handle(), &bytes, extension);
+    }
+    upload_to_r2(
+        window.app_handle(),
+        &window.state::<R2State>(),
+        UploadSource::Bytes(bytes.into()),
+        "region-capture",
+        extension,
+        UploadOptions { window_label: Some(window.label()), capture_stages: true, ..Default::default() },
+    )
+    .await
+}
+
+#[cfg(target_os = "macos")]
+fn capture_region_core_graphics(window: &Window, include_shadow: bool)
+    -> std::result::Result<std::path::PathBuf, String>
+{
+    use core_graphics::{
+        display::{kCGWindowListOptionOnScreenBelowWindow,
+                  CGWindowListCreateImage},
+        geometry::{CGRect, CGPoint, CGSize},
+    };
+    // 1. native window + frame in points
+    let ns_win = window.ns_window().map_err(|e| e.to_string())? as *mut Object;
+    let win_id: u32 = unsafe { msg_send![ns_win, windowNumber] };
+    let frame: NSRect = unsafe { msg_send![ns_win, frame] };
+
+    // 2. pick the actual display the window sits on
+    let ns_screen: *mut Object = unsafe { msg_send![ns_win, screen] };        // nil‑safe
+    let scale: f64 = unsafe { msg_send![ns_screen, backingScaleFactor] };
+
+    // 3. flip Y once (points → points, top‑left origin)
+    let screen_frame: NSRect = unsafe { msg_send![ns_screen, frame] };
+    let rect_pts = CGRect::new(
+        &CGPoint::new(frame.origin.x,                      // X unchanged
+                      screen_frame.size.height - frame.origin.y - frame.size.height),
+        &CGSize::new(frame.size.width, frame.size.height), // full window, no header math
+    );
+
+    // 4. We don't need to create or keep a CGImage reference - removed that part
+
+    // 5. Save directly to PNG using screencapture
+    emit_capture_stage(window, CaptureStage::Capturing);
+    screencapture_rect(window.app_handle(), rect_pts.origin.x, rect_pts.origin.y,
+                       rect_pts.size.width, rect_pts.size.height, include_shadow)
+}
+
+// Steps of a region capture, sent as `capture_stage` events so slow captures can show status
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "kebab-case")]
+enum CaptureStage {
+    FindingWindow,
+    Capturing,
+    Encoding,
+    Uploading,
+    Presigning,
+}
+
+fn emit_capture_stage<R: Runtime, E: Emitter<R>>(target: &E, stage: CaptureStage) {
+    debug!("Capture stage: {:?}", stage);
+    let _ = target.emit("capture_stage", stage);
+}
+
+// Capture the window's region and encode it using the platform's capture path.
+// Annotations are drawn at full resolution, before any downscaling.
+fn capture_region_bytes(
+    window: &Window,
+    format: CaptureFormat,
+    quality: u8,
+    max_dimension: Option<u32>,
+    annotations: &[LogicalRect],
+    include_shadow: bool,
+) -> std::result::Result<Vec<u8>, String> {
+    emit_capture_stage(window, CaptureStage::FindingWindow);
+
+    // screencapture already wrote a PNG, so skip decoding when there's nothing to draw
+    #[cfg(target_os = "macos")]
+    if annotations.is_empty() {
+        let path = capture_region_core_graphics(window, include_shadow)?;
+        emit_capture_stage(window, CaptureStage::Encoding);
+        return read_capture_file(path, format, quality, max_dimension);
+    }
+
+    let mut img = capture_region_image(window, include_shadow)?;
+    emit_capture_stage(window, CaptureStage::Encoding);
+    draw_annotations(&mut img, annotations, window.scale_factor().unwrap_or(1.0));
+    encode_capture(&fit_within(img, max_dimension), format, quality)
+}
+
+// Capture the window's region as raw pixels. `include_shadow` only applies on macOS.
+fn capture_region_image(window: &Window, include_shadow: bool) -> std::result::Result<image::RgbaImage, String> {
+    #[cfg(target_os = "macos")]
+    {
+        let path = capture_region_core_graphics(window, include_shadow)?;
+        let img = image::open(&path).map_err(|e| format!("Failed to read captured image: {}", e));
+        if let Err(e) = std::fs::remove_file(&path) {
+            warn!("Failed to remove temporary file {}: {}", path.display(), e);
+        }
+        return Ok(img?.to_rgba8());
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    {
+        // Straight from memory, no temporary file needed
+        let _ = include_shadow;
+        return capture_region_xcap(window);
+    }
+}
 
-                    // Parse the Mastra streaming format with different prefixes
-                    if line.len() >= 2 && line.chars().nth(1) == Some(':') {
-                        let prefix = line.chars().next().unwrap_or('?');
-                        let content = &line[2..];
+// A rectangle in logical pixels. Annotations are relative to the captured region's
+// top-left corner; scrolling-capture frames are in screen coordinates.
+#[derive(Deserialize, Debug, Clone, Copy)]
+struct LogicalRect {
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+}
 
-                        match prefix {
-                            'f' => {
-                                // First message, typically contains messageId
-                                println!("Message start: {}", content);
-                            },
-                            '0' => {
-                                // Text content chunk - use efficient string handling
-                                if let Ok(content_json) = serde_json::from_str::<serde_json::Value>(content) {
-                                    if let Some(text) = content_json.as_str() {
-                                        // Accumulate text and only emit after a reasonable batch or time
-                                        accumulated_text.push_str(text);
-                                        
-                                        // Emit if we have enough text or enough time has passed
-                                        let now = std::time::Instant::now();
-                                        if accumulated_text.len() > 50 || now.duration_since(last_emit).as_millis() > 100 {
-                                            window.emit("chat_chunk", &accumulated_text)
-                                                .map_err(|e| format!("Failed to emit chat chunk: {}", e))?;
-                                            accumulated_text.clear();
-                                            last_emit = now;
-                                        }
-                                    }
-                                } else if content.starts_with('"') && content.ends_with('"') && content.len() >= 2 {
-                                    // Handle quoted content
-                                    let clean_content = &content[1..content.len()-1];
-                                    accumulated_text.push_str(clean_content);
-                                    
-                                    // Same emit logic as above
-                                    let now = std::time::Instant::now();
-                                    if accumulated_text.len() > 50 || now.duration_since(last_emit).as_millis() > 100 {
-                                        window.emit("chat_chunk", &accumulated_text)
-                                            .map_err(|e| format!("Failed to emit chat chunk: {}", e))?;
-                                        accumulated_text.clear();
-                                        last_emit = now;
-                                    }
-                                }
-                            },
-                            'e' | 'd' => {
-                                // End message or Done message
-                                println!("Stream end marker: {} - {}", prefix, content);
-                                
-                                // Emit any remaining accumulated text
-                                if !accumulated_text.is_empty() {
-                                    window.emit("chat_chunk", &accumulated_text)
-                                        .map_err(|e| format!("Failed to emit final chat chunk: {}", e))?;
-                                    accumulated_text.clear();
-                                }
-                            },
-                            '3' => {
-                                // Error message
-                                println!("Error message: {}", content);
-                                // Strip quotes if present in error message
-                                let error_content = if content.starts_with('"') && content.ends_with('"') && content.len() >= 2 {
-                                    &content[1..content.len()-1]
-                                } else {
-                                    content
-                                };
-                                window.emit("chat_stream_error", error_content)
-                                    .map_err(|e| format!("Failed to emit error: {}", e))?;
-                            },
-                            _ => {
-                                // Unknown prefix, try to extract useful content
-                                println!("Unknown prefix: {} - content: {}", prefix, content);
-                            }
-                        }
-                    } else if line.starts_with("data: ") {
-                        // Handle standard SSE format as fallback
-                        let data = &line[6..]; // Skip "data: " prefix
+const ANNOTATION_COLOR: image::Rgba<u8> = image::Rgba([255, 59, 48, 255]);
+// Border thickness in logical pixels
+const ANNOTATION_THICKNESS: f64 = 3.0;
 
-                        if data == "[DONE]" {
-                            println!("Stream complete marker received");
-                            // Emit any remaining text
-                            if !accumulated_text.is_empty() {
-                                window.emit("chat_chunk", &accumulated_text)
-                                    .map_err(|e| format!("Failed to emit final SSE chat chunk: {}", e))?;
-                                accumulated_text.clear();
-                            }
-                            continue;
-                        }
+// Outline each rectangle on the captured image; `scale` converts logical to image pixels
+fn draw_annotations(img: &mut image::RgbaImage, annotations: &[LogicalRect], scale: f64) {
+    use imageproc::drawing::draw_hollow_rect_mut;
+    use imageproc::rect::Rect;
 
-                        // Try to parse data content
-                        if let Ok(json_value) = serde_json::from_str::<serde_json::Value>(data) {
-                            if let Some(text) = json_value.get("text").and_then(|t| t.as_str()) {
-                                accumulated_text.push_str(text);
-                                
-                                // Same emit logic
-                                let now = std::time::Instant::now();
-                                if accumulated_text.len() > 50 || now.duration_since(last_emit).as_millis() > 100 {
-                                    window.emit("chat_chunk", &accumulated_text)
-                                        .map_err(|e| format!("Failed to emit chat chunk: {}", e))?;
-                                    accumulated_text.clear();
-                                    last_emit = now;
-                                }
-                            }
-                        } else if !data.is_empty() {
-                            accumulated_text.push_str(data);
-                            
-                            // Same emit logic
-                            let now = std::time::Instant::now();
-                            if accumulated_text.len() > 50 || now.duration_since(last_emit).as_millis() > 100 {
-                                window.emit("chat_chunk", &accumulated_text)
-                                    .map_err(|e| format!("Failed to emit raw SSE chunk: {}", e))?;
-                                accumulated_text.clear();
-                                last_emit = now;
-                            }
-                        }
-                    }
-                }
-            }
-            Err(e) => {
-                // Error reading from the stream
-                let stream_error_msg = format!("Error reading stream from Mastra: {}", e);
-                eprintln!("{}", stream_error_msg);
-                window.emit("chat_stream_error", &stream_error_msg)
-                    .map_err(|e| format!("Failed to emit stream error event: {}", e))?;
-                // Terminate processing on stream error
-                return Err(stream_error_msg);
+    let thickness = (ANNOTATION_THICKNESS * scale).round().max(1.0) as i32;
+    for annotation in annotations {
+        let x = (annotation.x * scale).round() as i32;
+        let y = (annotation.y * scale).round() as i32;
+        let width = (annotation.width * scale).round() as i32;
+        let height = (annotation.height * scale).round() as i32;
+        // Nested outlines, growing inward, give the border its thickness
+        for inset in 0..thickness {
+            let (w, h) = (width - 2 * inset, height - 2 * inset);
+            if w <= 0 || h <= 0 {
+                break;
             }
+            draw_hollow_rect_mut(img, Rect::at(x + inset, y + inset).of_size(w as u32, h as u32), ANNOTATION_COLOR);
         }
     }
+}
+
+// Time the frontend gets to hide overlays after `capture_starting`
+const DEFAULT_CAPTURE_SETTLE_MS: u64 = 120;
 
-    // Emit any remaining text before signaling the end
-    if !accumulated_text.is_empty() {
-        window.emit("chat_chunk", &accumulated_text)
-            .map_err(|e| format!("Failed to emit final chat chunk: {}", e))?;
+#[tauri::command]
+#[tracing::instrument(skip_all, fields(window = %window.label()))]
+async fn capture_region_and_upload(
+    window: Window,
+    format: Option<String>,
+    quality: Option<u8>,
+    copy: Option<bool>,
+    max_dimension: Option<u32>,
+    keep_local: Option<bool>,
+    settle_ms: Option<u64>,
+    annotations: Option<Vec<LogicalRect>>,
+    include_shadow: Option<bool>,
+) -> std::result::Result<UploadResult, AppError> {
+    let format = CaptureFormat::parse(format.as_deref()).map_err(AppError::InvalidInput)?;
+    ensure_screen_capture_permission()?;
+
+    // Give the frontend a moment to hide overlays so they don't end up in the shot
+    let _ = window.emit("capture_starting", ());
+    tokio::time::sleep(Duration::from_millis(settle_ms.unwrap_or(DEFAULT_CAPTURE_SETTLE_MS))).await;
+    let annotations = annotations.unwrap_or_default();
+    let captured = capture_region_bytes(
+        &window,
+        format,
+        quality.unwrap_or(DEFAULT_JPEG_QUALITY),
+        max_dimension,
+        &annotations,
+        include_shadow.unwrap_or(false),
+    );
+    let _ = window.emit("capture_finished", ());
+    let bytes = captured.map_err(AppError::CaptureFailed)?;
+    let dimensions = image::ImageReader::new(std::io::Cursor::new(&bytes))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok());
+    // Local storage mode already leaves the file on disk
+    let local_copy = (keep_local.unwrap_or(false) && !window.state::<AppConfig>().local_storage).then(|| bytes.clone());
+    let mut result = upload_capture_bytes(&window, bytes, format.extension()).await?;
+    if let Some(bytes) = local_copy {
+        let (_, path) = write_capture_to_app_data(window.app_handle(), &bytes, format.extension())?;
+        result.local_path = Some(path.to_string_lossy().into_owned());
     }
 
-    // Signal the end of the stream
-    println!("Emitting stream end"); // Debugging
-    window.emit("chat_stream_end", ()).map_err(|e| format!("Failed to emit stream end event: {}", e))?;
-    Ok(()) // Return Ok(()) as the stream finished successfully
+    // History is a convenience, so a failed insert doesn't fail the capture
+    if let Err(e) = record_capture(window.app_handle(), &result, dimensions).await {
+        warn!("{}", e);
+    }
+
+    // "Screenshot and share link" in one action; the upload already succeeded, so a
+    // clipboard failure is only logged
+    if copy.unwrap_or(false) {
+        if let Err(e) = copy_to_clipboard(window.app_handle().clone(), result.url.clone()) {
+            warn!("Failed to copy capture link to clipboard: {}", e);
+        }
+    }
+    Ok(result)
 }
-// --- /MODIFIED COMMAND ---
 
-// Define the structure for the return value
-#[derive(Serialize)]
-struct UploadResult {
-    key: String,
-    url: String,
+// Long enough to open a menu or hover something, short enough to catch a typo'd delay
+const MAX_CAPTURE_DELAY_SECS: u64 = 60;
+
+// Delayed capture for menus and hover states: ticks `capture_countdown` with the seconds left
+#[tauri::command]
+#[tracing::instrument(skip(window), fields(window = %window.label()))]
+async fn capture_region_after_delay(window: Window, delay_secs: u64) -> std::result::Result<UploadResult, AppError> {
+    if delay_secs > MAX_CAPTURE_DELAY_SECS {
+        return Err(AppError::InvalidInput(format!(
+            "Delay must be at most {} seconds",
+            MAX_CAPTURE_DELAY_SECS
+        )));
+    }
+    for remaining in (1..=delay_secs).rev() {
+        let _ = window.emit("capture_countdown", remaining);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    let _ = window.emit("capture_countdown", 0u64);
+    capture_region_and_upload(window, None, None, None, None, None, None, None, None).await
 }
 
-// --- R2 Upload Command ---
 #[tauri::command]
-// Modify the return type to use the UploadResult struct
-async fn upload_image_to_r2(file_path: String) -> tauri::Result<UploadResult> {
-    println!("Attempting to upload image from path: {}", file_path);
-
-    // Load R2 configuration from environment variables, map errors to anyhow::Error
-    let account_id = env::var("R2_ACCOUNT_ID")
-        .map_err(|e| anyhow!("R2_ACCOUNT_ID not set: {}", e))?;
-    let access_key_id = env::var("R2_ACCESS_KEY_ID")
-        .map_err(|e| anyhow!("R2_ACCESS_KEY_ID not set: {}", e))?;
-    let secret_access_key = env::var("R2_SECRET_ACCESS_KEY")
-        .map_err(|e| anyhow!("R2_SECRET_ACCESS_KEY not set: {}", e))?;
-    let bucket_name = env::var("R2_BUCKET_NAME")
-        .map_err(|e| anyhow!("R2_BUCKET_NAME not set: {}", e))?;
-
-    // Construct the R2 endpoint URL
-    let endpoint_url = format!("https://{}.r2.cloudflarestorage.com", account_id);
-    println!("Using R2 endpoint: {}", endpoint_url);
+fn copy_to_clipboard<R: Runtime>(app: AppHandle<R>, text: String) -> std::result::Result<(), String> {
+    use tauri_plugin_clipboard_manager::ClipboardExt;
 
-    // Configure AWS SDK with optimized retry settings
-    let region_provider = RegionProviderChain::first_try(Region::new("auto")); // R2 specific region
-    let shared_config = aws_config::defaults(aws_config::BehaviorVersion::latest())
-        .region(region_provider)
-        .endpoint_url(endpoint_url.clone()) // Clone endpoint_url for use here
-        .retry_config(aws_config::retry::RetryConfig::standard()
-            .with_max_attempts(3) // Limit retry attempts to reduce latency on failure
-            .with_initial_backoff(Duration::from_millis(100))) // Start retries quickly
-        .credentials_provider(aws_sdk_s3::config::Credentials::new(
-            &access_key_id,
-            &secret_access_key,
-            None, // session token
-            None, // expiry
-            "cloudflare-r2-provider", // provider name
-        ))
-        .load()
-        .await;
+    app.clipboard()
+        .write_text(text)
+        .map_err(|e| format!("Failed to write to clipboard: {}", e))
+}
 
-    let client = S3Client::new(&shared_config);
+// "Capture from clipboard": upload whatever image is on the clipboard as a PNG
+#[tauri::command]
+#[tracing::instrument(skip_all, fields(window = %window.label()))]
+async fn upload_clipboard_image(window: Window) -> std::result::Result<UploadResult, AppError> {
+    use tauri_plugin_clipboard_manager::ClipboardExt;
 
-    // Generate a unique key (filename) for the R2 object
-    let file_stem = Path::new(&file_path)
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .unwrap_or("upload");
-    let extension = Path::new(&file_path)
-        .extension()
-        .and_then(|s| s.to_str())
-        .unwrap_or("png"); // Default to png if no extension
-    let key = format!("{}-{}.{}", file_stem, Uuid::new_v4(), extension);
-    println!("Generated R2 key: {}", key);
+    let image = window
+        .app_handle()
+        .clipboard()
+        .read_image()
+        .map_err(|e| AppError::InvalidInput(format!("Clipboard does not contain an image: {}", e)))?;
+    let img = image::RgbaImage::from_raw(image.width(), image.height(), image.rgba().to_vec())
+        .ok_or_else(|| AppError::InvalidInput("Clipboard image data is malformed".to_string()))?;
+    let bytes = encode_capture(&img, CaptureFormat::Png, DEFAULT_JPEG_QUALITY)
+        .map_err(AppError::Internal)?;
+    upload_capture_bytes(&window, bytes, CaptureFormat::Png.extension()).await
+}
 
-    // Create ByteStream from the file path, map error to anyhow::Error
-    let body = ByteStream::from_path(Path::new(&file_path))
-        .await
-        .map_err(|e| anyhow!("Failed to read file '{}' for upload: {}", file_path, e))?;
+// Open a link (e.g. an uploaded capture's presigned URL) in the default browser
+#[tauri::command]
+fn open_url<R: Runtime>(app: AppHandle<R>, url: String) -> std::result::Result<(), String> {
+    use tauri_plugin_opener::OpenerExt;
 
-    // Upload to R2
-    println!("Uploading to bucket: {}", bucket_name);
-    let _put_object_output = client.put_object()
-        .bucket(&bucket_name)
-        .key(&key)
-        // Add appropriate content type if possible based on extension
-        .content_type(match extension.to_lowercase().as_str() {
-            "jpg" | "jpeg" => "image/jpeg",
-            "png" => "image/png",
-            "gif" => "image/gif",
-            "webp" => "image/webp",
-            _ => "application/octet-stream",
-        })
-        .body(body)
-        .send()
-        .await
-        .map_err(|e| {
-             let sdk_error = e.into_service_error();
-             let error_message = format!("Failed to upload to R2: {:?}", sdk_error);
-             eprintln!("{}", error_message);
-             anyhow!(error_message) // Convert SdkError to anyhow::Error
-        })?;
+    let parsed = reqwest::Url::parse(&url).map_err(|e| format!("Invalid URL '{}': {}", url, e))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!("Refusing to open '{}': only http and https URLs are allowed", url));
+    }
+    app.opener()
+        .open_url(parsed.as_str(), None::<&str>)
+        .map_err(|e| format!("Failed to open URL: {}", e))
+}
 
-    println!("Successfully uploaded {} to R2 bucket {}", key, bucket_name);
+// Same capture as capture_region_and_upload, but hands back the PNG bytes without touching R2
+#[tauri::command]
+#[tracing::instrument(skip_all, fields(window = %window.label()))]
+async fn capture_region_to_png(window: Window) -> std::result::Result<Vec<u8>, AppError> {
+    ensure_screen_capture_permission()?;
+    capture_region_bytes(&window, CaptureFormat::Png, DEFAULT_JPEG_QUALITY, None, &[], false)
+        .map_err(AppError::CaptureFailed)
+}
 
-    // Generate pre-signed URL with optimized configuration
-    // Use shorter expiration for better security and to avoid browser caching issues
-    let presigning_config = PresigningConfig::builder()
-        .expires_in(Duration::from_secs(1800)) // 30 minutes - balanced for security vs usability
-        .build()
-        .context("Failed to create presigning config")?;
+// Capture the window's region as a `data:image/png;base64,...` URL for an inline <img> preview,
+// without touching R2
+#[tauri::command]
+#[tracing::instrument(skip_all, fields(window = %window.label()))]
+async fn capture_region_preview(window: Window) -> std::result::Result<String, AppError> {
+    use base64::Engine;
 
-    println!("Generating pre-signed URL for key: {}", key);
-    let presigned_request = client.get_object()
-        .bucket(&bucket_name)
-        .key(&key)
-        .presigned(presigning_config)
-        .await
-        .context("Failed to generate pre-signed URL")?;
+    ensure_screen_capture_permission()?;
+    let bytes = capture_region_bytes(&window, CaptureFormat::Png, DEFAULT_JPEG_QUALITY, None, &[], false)
+        .map_err(AppError::CaptureFailed)?;
+    Ok(format!("data:image/png;base64,{}", base64::engine::general_purpose::STANDARD.encode(bytes)))
+}
 
-    let presigned_url = presigned_request.uri().to_string();
-    println!("Generated pre-signed URL: {}", presigned_url);
+#[cfg(target_os = "macos")]
+#[link(name = "Vision", kind = "framework")]
+extern "C" {}
 
-    // Return both the key and the URL
-    Ok(UploadResult {
-        key,
-        url: presigned_url,
-    })
+// Recognize text in an image file with the Vision framework, one line per observation
+#[cfg(target_os = "macos")]
+fn recognize_text_in_file(path: &Path) -> std::result::Result<String, String> {
+    use objc::runtime::{BOOL, NO, YES};
+    use std::ffi::{CStr, CString};
+
+    let path_c = CString::new(path.to_string_lossy().as_bytes())
+        .map_err(|e| format!("Invalid capture path: {}", e))?;
+
+    unsafe {
+        let pool: *mut Object = msg_send![class!(NSAutoreleasePool), new];
+        let path_ns: *mut Object = msg_send![class!(NSString), stringWithUTF8String: path_c.as_ptr()];
+        let url: *mut Object = msg_send![class!(NSURL), fileURLWithPath: path_ns];
+
+        let request: *mut Object = msg_send![class!(VNRecognizeTextRequest), new];
+        let _: () = msg_send![request, setRecognitionLevel: 0isize]; // VNRequestTextRecognitionLevelAccurate
+        let _: () = msg_send![request, setUsesLanguageCorrection: YES];
+
+        let options: *mut Object = msg_send![class!(NSDictionary), dictionary];
+        let handler: *mut Object = msg_send![class!(VNImageRequestHandler), alloc];
+        let handler: *mut Object = msg_send![handler, initWithURL: url options: options];
+        let requests: *mut Object = msg_send![class!(NSArray), arrayWithObject: request];
+
+        let mut error: *mut Object = std::ptr::null_mut();
+        let ok: BOOL = msg_send![handler, performRequests: requests error: &mut error];
+        let result = if ok == NO {
+            let description = if error.is_null() {
+                "unknown error".to_string()
+            } else {
+                let description: *mut Object = msg_send![error, localizedDescription];
+                let utf8: *const std::os::raw::c_char = msg_send![description, UTF8String];
+                CStr::from_ptr(utf8).to_string_lossy().into_owned()
+            };
+            Err(format!("Text recognition failed: {}", description))
+        } else {
+            let observations: *mut Object = msg_send![request, results];
+            let count: usize = if observations.is_null() { 0 } else { msg_send![observations, count] };
+            let mut lines = Vec::with_capacity(count);
+            for i in 0..count {
+                let observation: *mut Object = msg_send![observations, objectAtIndex: i];
+                let candidates: *mut Object = msg_send![observation, topCandidates: 1usize];
+                let candidate_count: usize = msg_send![candidates, count];
+                if candidate_count == 0 {
+                    continue;
+                }
+                let candidate: *mut Object = msg_send![candidates, objectAtIndex: 0usize];
+                let text: *mut Object = msg_send![candidate, string];
+                let utf8: *const std::os::raw::c_char = msg_send![text, UTF8String];
+                lines.push(CStr::from_ptr(utf8).to_string_lossy().into_owned());
+            }
+            Ok(lines.join("\n"))
+        };
+
+        let _: () = msg_send![handler, release];
+        let _: () = msg_send![request, release];
+        let _: () = msg_send![pool, drain];
+        result
+    }
 }
-// --- /R2 Upload Command ---
 
-#[tauri::command]
-async fn open_drag_window<R: Runtime>(app: AppHandle<R>) -> Result<()> {
+// Recognize text in an image file with the `tesseract` CLI
+#[cfg(not(target_os = "macos"))]
+fn recognize_text_in_file(path: &Path) -> std::result::Result<String, String> {
+    use std::process::Command;
 
-    // Check if the window already exists
-    if let Some(window) = app.get_webview_window("drag-chat") {
-        // If it exists, bring it to the front
-        window.set_focus()?;
-    } else {
-        // If it doesn't exist, create it
-        let builder = tauri::WebviewWindowBuilder::new(&app, "drag-chat", WebviewUrl::App("drag.html".into()))
-            .title("Drag Chat")
-            .inner_size(420.0, 300.0)
-            .position(200.0, 200.0)
-            .transparent(true) 
-            .decorations(false) // No window decorations (title bar, etc.)
-            .resizable(true)
-            .skip_taskbar(true)
-            .focused(true)
-            .shadow(false)
-            .always_on_top(true); // Let the user move it behind other windows
+    let output = Command::new("tesseract")
+        .arg(path)
+        .arg("stdout")
+        .output()
+        .map_err(|e| format!("Failed to run tesseract (is it installed and on PATH?): {}", e))?;
 
-        // Create the window
-        let _window = builder.build()?;
+    if !output.status.success() {
+        return Err(format!("tesseract failed: {}", String::from_utf8_lossy(&output.stderr)));
     }
-    Ok(())
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
 }
 
+// Capture the window's region and return the text in it instead of uploading
 #[tauri::command]
-async fn close_drag_window<R: Runtime>(app: AppHandle<R>) -> Result<()> {
-    if let Some(window) = app.get_webview_window("drag-chat") {
-        window.close()?;
+#[tracing::instrument(skip_all, fields(window = %window.label()))]
+async fn capture_region_and_ocr(window: Window) -> std::result::Result<String, AppError> {
+    ensure_screen_capture_permission()?;
+    let bytes = capture_region_bytes(&window, CaptureFormat::Png, DEFAULT_JPEG_QUALITY, None, &[], false)
+        .map_err(AppError::CaptureFailed)?;
+
+    // Both OCR engines read from disk
+    let path = capture_cache_path(window.app_handle(), "region-ocr", "png").map_err(AppError::OcrFailed)?;
+    std::fs::write(&path, &bytes)
+        .map_err(|e| AppError::OcrFailed(format!("Failed to write capture for OCR: {}", e)))?;
+    let text = recognize_text_in_file(&path).map_err(AppError::OcrFailed);
+    if let Err(e) = std::fs::remove_file(&path) {
+        warn!("Failed to remove temporary file {}: {}", path.display(), e);
     }
-    Ok(())
+    text
 }
 
-#[cfg(not(target_os = "macos"))]
-async fn capture_region_xcap(window: Window) -> std::result::Result<UploadResult, String> {
-    use xcap::Window as XcapWindow;
-    use image::RgbaImage;
-    use std::io::Cursor;
+// Capture an arbitrary screen rectangle (logical pixels), e.g. from a snipping overlay
+#[tauri::command]
+#[tracing::instrument(skip_all, fields(window = %window.label()))]
+async fn capture_rect_and_upload(
+    window: Window,
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+) -> std::result::Result<UploadResult, AppError> {
+    if width <= 0.0 || height <= 0.0 {
+        return Err(AppError::InvalidInput(format!(
+            "Invalid capture size {}x{}: width and height must be positive",
+            width, height
+        )));
+    }
+    ensure_screen_capture_permission()?;
 
-    // Get window title to find the corresponding xcap window
-    let window_title = window.title().map_err(|e| format!("Failed to get window title: {}", e))?;
-    let window_id = window.label();
+    #[cfg(target_os = "macos")]
+    {
+        // screencapture -R takes points, which match Tauri's logical pixels
+        let dest = screencapture_rect(window.app_handle(), x, y, width, height, false).map_err(AppError::CaptureFailed)?;
+        let bytes = read_capture_file(dest, CaptureFormat::Png, DEFAULT_JPEG_QUALITY, None)
+            .map_err(AppError::CaptureFailed)?;
+        return upload_capture_bytes(&window, bytes, CaptureFormat::Png.extension()).await;
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    {
+        let img = capture_screen_rect_xcap(x, y, width, height).map_err(AppError::CaptureFailed)?;
+        let bytes = encode_capture(&img, CaptureFormat::Png, DEFAULT_JPEG_QUALITY)
+            .map_err(AppError::CaptureFailed)?;
+        return upload_capture_bytes(&window, bytes, CaptureFormat::Png.extension()).await;
+    }
+}
 
-    println!("Looking for window with label: {} and title: {}", window_id, window_title);
+// Bounds in points (top-left origin) of the frontmost normal window that isn't one of Eye's.
+// CGWindowListCopyWindowInfo lists on-screen windows front to back.
+#[cfg(target_os = "macos")]
+fn frontmost_external_window_bounds() -> std::result::Result<(f64, f64, f64, f64), String> {
+    use core_foundation::array::CFArray;
+    use core_foundation::base::{CFType, TCFType};
+    use core_foundation::dictionary::CFDictionary;
+    use core_foundation::number::CFNumber;
+    use core_foundation::string::CFString;
+    use core_graphics::geometry::{CGPoint, CGRect, CGSize};
 
-    // Find all windows
-    let xcap_windows = XcapWindow::all().map_err(|e| format!("Failed to get window list: {}", e))?;
-    
-    // Try to find our window by title
-    let mut found_window = None;
-    for xcap_window in &xcap_windows {
-        let title = xcap_window.title().map_err(|e| format!("Failed to get xcap window title: {}", e))?;
-        println!("Found window: {}", title);
-        
-        // Match on partial title since Tauri might add app name to title
-        if title.contains(&window_title) || title.contains(window_id) {
-            found_window = Some(xcap_window.clone());
-            println!("Found matching window: {}", title);
-            break;
-        }
+    const ON_SCREEN_ONLY: u32 = 1 << 0;
+    const EXCLUDE_DESKTOP_ELEMENTS: u32 = 1 << 4;
+
+    let list = unsafe { CGWindowListCopyWindowInfo(ON_SCREEN_ONLY | EXCLUDE_DESKTOP_ELEMENTS, 0) };
+    if list.is_null() {
+        return Err("Failed to read the window list".to_string());
     }
+    let windows: CFArray<CFDictionary<CFString, CFType>> = unsafe { CFArray::wrap_under_create_rule(list) };
+    let number = |info: &CFDictionary<CFString, CFType>, key: &'static str| {
+        info.find(CFString::from_static_string(key))
+            .and_then(|value| value.downcast::<CFNumber>())
+            .and_then(|value| value.to_i64())
+    };
 
-    // If we can't find by title, use window dimensions as fallback
-    if found_window.is_none() {
-        println!("Couldn't find window by title, falling back to position and size matching");
-        
-        // Get window geometry in physical pixels
-        let position = window.outer_position().map_err(|e| format!("Failed to get window position: {}", e))?;
-        let size = window.outer_size().map_err(|e| format!("Failed to get window size: {}", e))?;
-        let scale_factor = window.scale_factor().map_err(|e| format!("Failed to get scale factor: {}", e))?;
-        
-        // Convert from logical to physical pixels
-        let x = (position.x as f64 * scale_factor) as i32;
-        let y = (position.y as f64 * scale_factor) as i32;
-        let w = (size.width as f64 * scale_factor) as u32;
-        let h = (size.height as f64 * scale_factor) as u32;
-        
-        println!("Looking for window at ({}, {}) with size {}x{}", x, y, w, h);
-        
-        // Find window with closest matching position and size
-        for xcap_window in &xcap_windows {
-            if xcap_window.is_minimized().map_err(|e| format!("Failed to check if window is minimized: {}", e))? {
-                continue;
-            }
-            
-            let wx = xcap_window.x().map_err(|e| format!("Failed to get xcap window x: {}", e))?;
-            let wy = xcap_window.y().map_err(|e| format!("Failed to get xcap window y: {}", e))?;
-            let ww = xcap_window.width().map_err(|e| format!("Failed to get xcap window width: {}", e))?;
-            let wh = xcap_window.height().map_err(|e| format!("Failed to get xcap window height: {}", e))?;
-            
-            // Check if positions are close (within 20 pixels)
-            let position_close = (wx - x).abs() < 20 && (wy - y).abs() < 20;
-            // Check if sizes are close (within 20 pixels)
-            let size_close = ((ww as i32) - (w as i32)).abs() < 20 && ((wh as i32) - (h as i32)).abs() < 20;
-            
-            if position_close && size_close {
-                let title = xcap_window.title().unwrap_or_else(|_| "Unknown".to_string());
-                println!("Found window by position/size: {}", title);
-                found_window = Some(xcap_window.clone());
-                break;
-            }
+    let own_pid = std::process::id() as i64;
+    for info in windows.iter() {
+        // Layer 0 holds ordinary app windows; menu bar, dock and overlays sit above it
+        if number(&info, "kCGWindowLayer") != Some(0) || number(&info, "kCGWindowOwnerPID") == Some(own_pid) {
+            continue;
+        }
+        let Some(bounds) = info.find(CFString::from_static_string("kCGWindowBounds")) else {
+            continue;
+        };
+        let mut rect = CGRect::new(&CGPoint::new(0.0, 0.0), &CGSize::new(0.0, 0.0));
+        let parsed = unsafe { CGRectMakeWithDictionaryRepresentation(bounds.as_CFTypeRef() as _, &mut rect) };
+        if parsed && rect.size.width > 1.0 && rect.size.height > 1.0 {
+            return Ok((rect.origin.x, rect.origin.y, rect.size.width, rect.size.height));
         }
     }
+    Err("No window from another app is on screen".to_string())
+}
+
+// The focused window of another app, or failing that the topmost visible one
+#[cfg(not(target_os = "macos"))]
+fn capture_active_window_xcap() -> std::result::Result<image::RgbaImage, String> {
+    let own_pid = std::process::id();
+    let windows = xcap::Window::all().map_err(|e| format!("Failed to get window list: {}", e))?;
+    let external: Vec<&xcap::Window> = windows
+        .iter()
+        .filter(|w| w.pid().map_or(false, |pid| pid != own_pid))
+        .filter(|w| !w.is_minimized().unwrap_or(false))
+        .collect();
+    let target = external
+        .iter()
+        .find(|w| w.is_focused().unwrap_or(false))
+        .or_else(|| external.first())
+        .ok_or_else(|| "No window from another app is on screen".to_string())?;
+    debug!("Capturing window: {}", target.title().unwrap_or_default());
+    target
+        .capture_image()
+        .map_err(|e| format!("Failed to capture window image: {}", e))
+}
+
+// Capture whatever app the user is looking at, skipping Eye's own windows
+#[tauri::command]
+#[tracing::instrument(skip_all, fields(window = %window.label()))]
+async fn capture_active_window_and_upload(window: Window) -> std::result::Result<UploadResult, AppError> {
+    ensure_screen_capture_permission()?;
+
+    #[cfg(target_os = "macos")]
+    {
+        let (x, y, width, height) = frontmost_external_window_bounds().map_err(AppError::CaptureFailed)?;
+        let dest = screencapture_rect(window.app_handle(), x, y, width, height, false).map_err(AppError::CaptureFailed)?;
+        let bytes = read_capture_file(dest, CaptureFormat::Png, DEFAULT_JPEG_QUALITY, None)
+            .map_err(AppError::CaptureFailed)?;
+        return upload_capture_bytes(&window, bytes, CaptureFormat::Png.extension()).await;
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    {
+        let img = capture_active_window_xcap().map_err(AppError::CaptureFailed)?;
+        let bytes = encode_capture(&img, CaptureFormat::Png, DEFAULT_JPEG_QUALITY)
+            .map_err(AppError::CaptureFailed)?;
+        return upload_capture_bytes(&window, bytes, CaptureFormat::Png.extension()).await;
+    }
+}
 
-    // Capture the window if found
-    let full_img = if let Some(xcap_window) = found_window {
-        xcap_window.capture_image().map_err(|e| format!("Failed to capture window image: {}", e))?
-    } else {
-        // Fallback to original method if window can't be found
-        println!("Falling back to screen region capture");
-        
-        // Get window geometry in physical pixels
-        let position = window.outer_position().map_err(|e| format!("Failed to get window position: {}", e))?;
-        let size = window.outer_size().map_err(|e| format!("Failed to get window size: {}", e))?;
-        let scale_factor = window.scale_factor().map_err(|e| format!("Failed to get scale factor: {}", e))?;
-        
-        // Convert from logical to physical pixels
-        let x = (position.x as f64 * scale_factor) as i32;
-        let y = (position.y as f64 * scale_factor) as i32;
-        let w = (size.width as f64 * scale_factor) as u32;
-        let h = (size.height as f64 * scale_factor) as u32;
-        
-        // Use the original monitor-based capture as fallback
-        use xcap::Monitor;
-        let monitor = Monitor::from_point(x, y).map_err(|e| format!("Failed to get monitor at point ({}, {}): {}", x, y, e))?;
-        let monitor_img = monitor.capture_image().map_err(|e| format!("Failed to capture monitor image: {}", e))?;
-        
-        // Crop to the rectangle under our window
-        image::imageops::crop_imm(&monitor_img, x as u32, y as u32, w, h).to_image()
-    };
+// Capture another app's window by (part of) its title, e.g. "Safari", and upload it
+#[tauri::command]
+#[tracing::instrument(skip(window), fields(window = %window.label()))]
+async fn capture_window_by_title(window: Window, title_substring: String) -> std::result::Result<UploadResult, AppError> {
+    let title_substring = title_substring.trim();
+    if title_substring.is_empty() {
+        return Err(AppError::InvalidInput("Window title must not be empty".to_string()));
+    }
+    ensure_screen_capture_permission()?;
 
-    // The rest of the process remains the same
-    // Encode the image to PNG format
-    let mut png_bytes = Vec::new();
-    let mut cursor = Cursor::new(&mut png_bytes);
-    full_img.write_to(&mut cursor, image::ImageFormat::Png)
-        .map_err(|e| format!("Failed to encode image as PNG: {}", e))?;
-
-    // Create a temporary file to store the PNG data
-    use std::env::temp_dir;
-    use std::fs::File;
-    use std::io::Write;
-    use uuid::Uuid;
-
-    let temp_path = temp_dir().join(format!("region-capture-{}.png", Uuid::new_v4()));
-    let temp_path_str = temp_path.to_string_lossy().to_string();
-    
-    let mut file = File::create(&temp_path)
-        .map_err(|e| format!("Failed to create temporary file: {}", e))?;
-    file.write_all(&png_bytes)
-        .map_err(|e| format!("Failed to write to temporary file: {}", e))?;
-    file.flush()
-        .map_err(|e| format!("Failed to flush temporary file: {}", e))?;
-
-    // Use the existing R2 upload functionality
-    let upload_result = upload_image_to_r2(temp_path_str.clone())
-        .await
-        .map_err(|e| format!("Failed to upload image to R2: {}", e))?;
+    let xcap_windows = xcap::Window::all()
+        .map_err(|e| AppError::CaptureFailed(format!("Failed to get window list: {}", e)))?;
+    let target = find_xcap_window_by_title(&xcap_windows, &[title_substring])
+        .map_err(AppError::CaptureFailed)?
+        .ok_or_else(|| {
+            let titles: Vec<String> = xcap_windows
+                .iter()
+                .filter_map(|w| w.title().ok())
+                .filter(|title| !title.trim().is_empty())
+                .collect();
+            AppError::NotFound(format!(
+                "No window title contains '{}'. Available windows: {}",
+                title_substring,
+                titles.join(", ")
+            ))
+        })?;
 
-    // Optional: Clean up the temporary file (best effort)
-    if let Err(e) = std::fs::remove_file(&temp_path) {
-        eprintln!("Warning: Failed to remove temporary file {}: {}", temp_path_str, e);
+    let img = target
+        .capture_image()
+        .map_err(|e| AppError::CaptureFailed(format!("Failed to capture window image: {}", e)))?;
+    let bytes = encode_capture(&img, CaptureFormat::Png, DEFAULT_JPEG_QUALITY)
+        .map_err(AppError::CaptureFailed)?;
+    upload_capture_bytes(&window, bytes, CaptureFormat::Png.extension()).await
+}
+
+// Capture a screen rectangle (logical pixels) into memory on either platform
+fn capture_screen_rect_image<R: Runtime>(app: &AppHandle<R>, rect: LogicalRect) -> std::result::Result<image::RgbaImage, String> {
+    #[cfg(target_os = "macos")]
+    {
+        let dest = screencapture_rect(app, rect.x, rect.y, rect.width, rect.height, false)?;
+        let img = image::open(&dest).map_err(|e| format!("Failed to read captured image: {}", e));
+        if let Err(e) = std::fs::remove_file(&dest) {
+            warn!("Failed to remove temporary file {}: {}", dest.display(), e);
+        }
+        return Ok(img?.to_rgba8());
     }
 
-    Ok(upload_result)
+    #[cfg(not(target_os = "macos"))]
+    {
+        let _ = app;
+        return capture_screen_rect_xcap(rect.x, rect.y, rect.width, rect.height);
+    }
 }
 
-#[cfg(target_os = "macos")]
-async fn capture_region_core_graphics(window: Window)
-    -> std::result::Result<UploadResult, String>
-{
-    use core_graphics::{
-        display::{kCGWindowListOptionOnScreenBelowWindow,
-                  CGWindowListCreateImage},
-        geometry::{CGRect, CGPoint, CGSize},
-    };
-    use std::{env::temp_dir, process::Command};
-    use uuid::Uuid;
+// Stack frames top to bottom, left-aligned, on a transparent canvas as wide as the widest frame
+fn stitch_vertically(frames: &[image::RgbaImage]) -> image::RgbaImage {
+    let width = frames.iter().map(|frame| frame.width()).max().unwrap_or(0);
+    let height = frames.iter().map(|frame| frame.height()).sum();
+    let mut canvas = image::RgbaImage::new(width, height);
+    let mut y = 0i64;
+    for frame in frames {
+        image::imageops::overlay(&mut canvas, frame, 0, y);
+        y += frame.height() as i64;
+    }
+    canvas
+}
 
-    // 1. native window + frame in points
-    let ns_win = window.ns_window().map_err(|e| e.to_string())? as *mut Object;
-    let win_id: u32 = unsafe { msg_send![ns_win, windowNumber] };
-    let frame: NSRect = unsafe { msg_send![ns_win, frame] };
+const MAX_SCROLL_FRAMES: usize = 20;
 
-    // 2. pick the actual display the window sits on
-    let ns_screen: *mut Object = unsafe { msg_send![ns_win, screen] };        // nil‑safe
-    let scale: f64 = unsafe { msg_send![ns_screen, backingScaleFactor] };
+// Capture a long, scrolling view: each rect is captured in order (waiting `frame_delay_ms`
+// after each so the frontend can scroll on `scroll_frame_captured`), then the frames are
+// stitched into one tall PNG and uploaded
+#[tauri::command]
+#[tracing::instrument(skip_all, fields(window = %window.label(), frames = frames.len()))]
+async fn capture_scrolling_region(
+    window: Window,
+    frames: Vec<LogicalRect>,
+    frame_delay_ms: Option<u64>,
+) -> std::result::Result<UploadResult, AppError> {
+    if frames.is_empty() || frames.len() > MAX_SCROLL_FRAMES {
+        return Err(AppError::InvalidInput(format!(
+            "Expected between 1 and {} frames, got {}",
+            MAX_SCROLL_FRAMES,
+            frames.len()
+        )));
+    }
+    if let Some(rect) = frames.iter().find(|rect| rect.width <= 0.0 || rect.height <= 0.0) {
+        return Err(AppError::InvalidInput(format!(
+            "Invalid frame size {}x{}: width and height must be positive",
+            rect.width, rect.height
+        )));
+    }
+    ensure_screen_capture_permission()?;
 
-    // 3. flip Y once (points → points, top‑left origin)
-    let screen_frame: NSRect = unsafe { msg_send![ns_screen, frame] };
-    let rect_pts = CGRect::new(
-        &CGPoint::new(frame.origin.x,                      // X unchanged
-                      screen_frame.size.height - frame.origin.y - frame.size.height),
-        &CGSize::new(frame.size.width, frame.size.height), // full window, no header math
-    );
+    let mut images = Vec::with_capacity(frames.len());
+    for (index, rect) in frames.iter().enumerate() {
+        images.push(capture_screen_rect_image(window.app_handle(), *rect).map_err(AppError::CaptureFailed)?);
+        let _ = window.emit("scroll_frame_captured", index);
+        if index + 1 < frames.len() {
+            tokio::time::sleep(Duration::from_millis(frame_delay_ms.unwrap_or(0))).await;
+        }
+    }
 
-    // 4. We don't need to create or keep a CGImage reference - removed that part
+    let stitched = stitch_vertically(&images);
+    let bytes = encode_capture(&stitched, CaptureFormat::Png, DEFAULT_JPEG_QUALITY)
+        .map_err(AppError::CaptureFailed)?;
+    upload_capture_bytes(&window, bytes, CaptureFormat::Png.extension()).await
+}
 
-    // 5. Save directly to PNG using screencapture 
-    let dest = temp_dir().join(format!("region-{}.png", Uuid::new_v4()));
-    let dest_path = dest.to_string_lossy().to_string();
-    
-    // Let Core Graphics write directly to the file
-    let output = Command::new("screencapture")
-        .args([
-            "-x",       // No sound
-            "-o",       // No shadow
-            "-R", &format!("{},{},{},{}", 
-                rect_pts.origin.x, rect_pts.origin.y,
-                rect_pts.size.width, rect_pts.size.height),
-            &dest_path
-        ])
-        .output()
-        .map_err(|e| format!("Failed to capture: {}", e))?;
-    
-    if !output.status.success() {
-        return Err(format!("screencapture command failed: {}", 
-            String::from_utf8_lossy(&output.stderr)));
+const MAX_GIF_FRAMES: u32 = 50;
+// GIF delays are in hundredths of a second and most viewers clamp anything shorter
+const MIN_GIF_INTERVAL_MS: u64 = 20;
+// Frames are downscaled to keep the encoded file (and the frames held in memory) small
+const MAX_GIF_DIMENSION: u32 = 800;
+
+// Record the window's region as a looping GIF: `frame_count` captures spaced `interval_ms`
+// apart, ticking `gif_frame_captured` with each frame's index
+#[tauri::command]
+#[tracing::instrument(skip(window), fields(window = %window.label()))]
+async fn capture_gif_and_upload(
+    window: Window,
+    frame_count: u32,
+    interval_ms: u64,
+) -> std::result::Result<UploadResult, AppError> {
+    if frame_count == 0 || frame_count > MAX_GIF_FRAMES {
+        return Err(AppError::InvalidInput(format!(
+            "Expected between 1 and {} frames, got {}",
+            MAX_GIF_FRAMES, frame_count
+        )));
+    }
+    let interval_ms = interval_ms.max(MIN_GIF_INTERVAL_MS);
+    ensure_screen_capture_permission()?;
+
+    let _ = window.emit("capture_starting", ());
+    tokio::time::sleep(Duration::from_millis(DEFAULT_CAPTURE_SETTLE_MS)).await;
+    let mut frames = Vec::with_capacity(frame_count as usize);
+    for index in 0..frame_count {
+        match capture_region_image(&window, false) {
+            Ok(img) => frames.push(fit_within(img, Some(MAX_GIF_DIMENSION))),
+            Err(e) => {
+                let _ = window.emit("capture_finished", ());
+                return Err(AppError::CaptureFailed(e));
+            }
+        }
+        let _ = window.emit("gif_frame_captured", index);
+        if index + 1 < frame_count {
+            tokio::time::sleep(Duration::from_millis(interval_ms)).await;
+        }
     }
+    let _ = window.emit("capture_finished", ());
 
-    // 6. upload (your existing helper)
-    let res = upload_image_to_r2(dest_path)
+    emit_capture_stage(&window, CaptureStage::Encoding);
+    let bytes = tokio::task::spawn_blocking(move || encode_gif(frames, interval_ms))
         .await
-        .map_err(|e| e.to_string())?;
-    let _ = std::fs::remove_file(dest);
-    Ok(res)
+        .map_err(|e| AppError::Internal(format!("GIF encoding task failed: {}", e)))?
+        .map_err(AppError::CaptureFailed)?;
+    upload_capture_bytes(&window, bytes, "gif").await
+}
+
+// Encode frames as an endlessly looping GIF. Frames that differ in size from the first
+// (the window moved to another display mid-recording) are resized to match.
+fn encode_gif(frames: Vec<image::RgbaImage>, interval_ms: u64) -> std::result::Result<Vec<u8>, String> {
+    let (width, height) = frames.first().map(|frame| frame.dimensions()).ok_or("No frames to encode")?;
+    let (gif_width, gif_height) = (
+        u16::try_from(width).map_err(|_| format!("Frame width {} is too large for a GIF", width))?,
+        u16::try_from(height).map_err(|_| format!("Frame height {} is too large for a GIF", height))?,
+    );
+    let delay = u16::try_from(interval_ms / 10).unwrap_or(u16::MAX);
+
+    let mut encoder = gif::Encoder::new(Vec::new(), gif_width, gif_height, &[])
+        .map_err(|e| format!("Failed to start GIF: {}", e))?;
+    encoder
+        .set_repeat(gif::Repeat::Infinite)
+        .map_err(|e| format!("Failed to set GIF looping: {}", e))?;
+    for frame in frames {
+        let frame = if frame.dimensions() == (width, height) {
+            frame
+        } else {
+            image::imageops::resize(&frame, width, height, image::imageops::FilterType::Triangle)
+        };
+        let mut pixels = frame.into_raw();
+        let mut gif_frame = gif::Frame::from_rgba_speed(gif_width, gif_height, &mut pixels, 10);
+        gif_frame.delay = delay;
+        encoder
+            .write_frame(&gif_frame)
+            .map_err(|e| format!("Failed to encode GIF frame: {}", e))?;
+    }
+    encoder.into_inner().map_err(|e| format!("Failed to finish GIF: {}", e))
+}
+
+// A connected display, in physical pixels; `index` is what capture_monitor_and_upload takes
+#[derive(Serialize)]
+struct MonitorInfo {
+    index: usize,
+    id: u32,
+    name: String,
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+    scale_factor: f32,
+    is_primary: bool,
 }
 
 #[tauri::command]
-async fn capture_region_and_upload(window: Window) -> std::result::Result<UploadResult, String> {
+fn list_monitors() -> std::result::Result<Vec<MonitorInfo>, String> {
+    let monitors = xcap::Monitor::all().map_err(|e| format!("Failed to list monitors: {}", e))?;
+    monitors
+        .iter()
+        .enumerate()
+        .map(|(index, monitor)| {
+            Ok(MonitorInfo {
+                index,
+                id: monitor.id()?,
+                name: monitor.name()?,
+                x: monitor.x()?,
+                y: monitor.y()?,
+                width: monitor.width()?,
+                height: monitor.height()?,
+                scale_factor: monitor.scale_factor()?,
+                is_primary: monitor.is_primary()?,
+            })
+        })
+        .collect::<std::result::Result<Vec<_>, xcap::XCapError>>()
+        .map_err(|e| format!("Failed to read monitor details: {}", e))
+}
+
+// Capture a whole display, by its index in the OS display list (0 is the main display)
+#[tauri::command]
+#[tracing::instrument(skip_all, fields(window = %window.label()))]
+async fn capture_monitor_and_upload(window: Window, index: usize) -> std::result::Result<UploadResult, AppError> {
+    ensure_screen_capture_permission()?;
+
     #[cfg(target_os = "macos")]
     {
-        // Added curly braces for clarity and to ensure return is from this block
-        return capture_region_core_graphics(window).await;
+        // screencapture numbers displays from 1 in CGGetActiveDisplayList order
+        let count = core_graphics::display::CGDisplay::active_displays()
+            .map_err(|e| AppError::CaptureFailed(format!("Failed to list displays: error {}", e)))?
+            .len();
+        if index >= count {
+            return Err(AppError::InvalidInput(format!(
+                "Monitor index {} is out of range: {} monitor(s) connected",
+                index, count
+            )));
+        }
+        let dest = screencapture_to_temp(window.app_handle(), &["-D", &(index + 1).to_string()], false)
+            .map_err(AppError::CaptureFailed)?;
+        let bytes = read_capture_file(dest, CaptureFormat::Png, DEFAULT_JPEG_QUALITY, None)
+            .map_err(AppError::CaptureFailed)?;
+        return upload_capture_bytes(&window, bytes, CaptureFormat::Png.extension()).await;
     }
 
     #[cfg(not(target_os = "macos"))]
     {
-        // Added curly braces for clarity and to ensure return is from this block
-        return capture_region_xcap(window).await; // existing body moved here
+        let monitors = xcap::Monitor::all()
+            .map_err(|e| AppError::CaptureFailed(format!("Failed to list monitors: {}", e)))?;
+        let monitor = monitors.get(index).ok_or_else(|| {
+            AppError::InvalidInput(format!(
+                "Monitor index {} is out of range: {} monitor(s) connected",
+                index,
+                monitors.len()
+            ))
+        })?;
+        let img = monitor
+            .capture_image()
+            .map_err(|e| AppError::CaptureFailed(format!("Failed to capture monitor image: {}", e)))?;
+        let bytes = encode_capture(&img, CaptureFormat::Png, DEFAULT_JPEG_QUALITY)
+            .map_err(AppError::CaptureFailed)?;
+        return upload_capture_bytes(&window, bytes, CaptureFormat::Png.extension()).await;
     }
 }
 
-#[cfg_attr(mobile, tauri::mobile_entry_point)]
-pub fn run() {
-    // Load .env file variables into environment
-    dotenvy::dotenv().expect("Failed to load .env file. Please ensure it exists in src-tauri/");
+// --- Global capture shortcut ---
+const DEFAULT_CAPTURE_SHORTCUT: &str = "CommandOrControl+Shift+2";
+
+// Accelerator currently bound to region capture
+struct CaptureShortcut(Mutex<String>);
+
+
+// The Eye window a shortcut capture should target: the focused one, else whichever is open
+fn active_eye_window<R: Runtime>(app: &AppHandle<R>) -> Option<Window<R>> {
+    let windows: Vec<Window<R>> = ["popup", "drag-chat"]
+        .iter()
+        .filter_map(|label| app.get_window(label))
+        .collect();
+    windows
+        .iter()
+        .find(|window| window.is_focused().unwrap_or(false))
+        .cloned()
+        .or_else(|| windows.into_iter().next())
+}
+
+fn handle_capture_shortcut(app: &AppHandle) {
+    let Some(window) = active_eye_window(app) else {
+        error!("Capture shortcut pressed but no Eye window is open");
+        return;
+    };
+    tauri::async_runtime::spawn(async move {
+        match capture_region_and_upload(window.clone(), None, None, None, None, None, None, None, None).await {
+            Ok(result) => {
+                let _ = window.emit("shortcut_capture_complete", &result);
+            }
+            Err(e) => {
+                error!("Shortcut capture failed: {}", e);
+                let _ = window.emit("shortcut_capture_error", &e);
+            }
+        }
+    });
+}
+
+#[tauri::command]
+fn set_capture_shortcut(
+    app: AppHandle,
+    shortcut: String,
+    current: State<'_, CaptureShortcut>,
+) -> std::result::Result<(), String> {
+    use tauri_plugin_global_shortcut::{GlobalShortcutExt, Shortcut};
+
+    let parsed: Shortcut = shortcut
+        .parse()
+        .map_err(|e| format!("Invalid shortcut '{}': {}", shortcut, e))?;
+
+    let mut current = current.0.lock().map_err(|e| format!("Failed to lock shortcut state: {}", e))?;
+    if current.parse::<Shortcut>().ok() == Some(parsed) {
+        return Ok(());
+    }
+
+    // Bind the new shortcut before releasing the old one, so a combo another app already
+    // owns leaves the current binding working
+    let global_shortcut = app.global_shortcut();
+    global_shortcut
+        .register(parsed)
+        .map_err(|e| format!("Failed to register shortcut '{}': {}", shortcut, e))?;
+    if let Err(e) = global_shortcut.unregister(current.as_str()) {
+        warn!("Failed to unregister shortcut {}: {}", current, e);
+    }
 
-    let migrations = vec![
+    *current = shortcut;
+    Ok(())
+}
+// --- /Global capture shortcut ---
+
+// Schema for notes.db, applied by the SQL plugin at startup and by reset_database
+fn notes_migrations() -> Vec<Migration> {
+    vec![
         Migration {
             version: 1,
             description: "create_initial_notes_table",
@@ -812,7 +4972,117 @@ pub fn run() {
                   );",
             kind: MigrationKind::Up,
         },
-    ];
+        Migration {
+            version: 2,
+            description: "create_chat_messages_table",
+            sql: "CREATE TABLE IF NOT EXISTS chat_messages (
+                      id INTEGER PRIMARY KEY AUTOINCREMENT,
+                      conversation_id TEXT NOT NULL,
+                      role TEXT NOT NULL,
+                      content TEXT NOT NULL,
+                      image_key TEXT,
+                      created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+                  );
+                  CREATE INDEX IF NOT EXISTS idx_chat_messages_conversation
+                      ON chat_messages (conversation_id, created_at);",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 3,
+            description: "create_conversations_table",
+            sql: "CREATE TABLE IF NOT EXISTS conversations (
+                      id TEXT PRIMARY KEY,
+                      title TEXT,
+                      created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                      updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+                  );",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 4,
+            description: "create_window_state_table",
+            sql: "CREATE TABLE IF NOT EXISTS window_state (
+                      label TEXT PRIMARY KEY,
+                      x REAL NOT NULL,
+                      y REAL NOT NULL,
+                      width REAL NOT NULL,
+                      height REAL NOT NULL,
+                      updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+                  );",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 5,
+            description: "create_notes_fts_table",
+            sql: "CREATE VIRTUAL TABLE IF NOT EXISTS notes_fts USING fts5(
+                      title, body, content='notes', content_rowid='id'
+                  );
+                  CREATE TRIGGER IF NOT EXISTS notes_fts_insert AFTER INSERT ON notes BEGIN
+                      INSERT INTO notes_fts (rowid, title, body) VALUES (new.id, new.title, new.body);
+                  END;
+                  CREATE TRIGGER IF NOT EXISTS notes_fts_delete AFTER DELETE ON notes BEGIN
+                      INSERT INTO notes_fts (notes_fts, rowid, title, body) VALUES ('delete', old.id, old.title, old.body);
+                  END;
+                  CREATE TRIGGER IF NOT EXISTS notes_fts_update AFTER UPDATE ON notes BEGIN
+                      INSERT INTO notes_fts (notes_fts, rowid, title, body) VALUES ('delete', old.id, old.title, old.body);
+                      INSERT INTO notes_fts (rowid, title, body) VALUES (new.id, new.title, new.body);
+                  END;
+                  INSERT INTO notes_fts (notes_fts) VALUES ('rebuild');",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 6,
+            description: "add_notes_image_key",
+            sql: "ALTER TABLE notes ADD COLUMN image_key TEXT;",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 7,
+            description: "add_conversations_system_prompt",
+            sql: "ALTER TABLE conversations ADD COLUMN system_prompt TEXT;",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 8,
+            description: "add_notes_updated_at",
+            sql: "ALTER TABLE notes ADD COLUMN updated_at TIMESTAMP;",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 9,
+            description: "create_captures_table",
+            sql: "CREATE TABLE IF NOT EXISTS captures (
+                      id INTEGER PRIMARY KEY AUTOINCREMENT,
+                      key TEXT NOT NULL,
+                      url TEXT NOT NULL,
+                      width INTEGER,
+                      height INTEGER,
+                      created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+                  );
+                  CREATE INDEX IF NOT EXISTS idx_captures_created_at ON captures (created_at);",
+            kind: MigrationKind::Up,
+        },
+    ]
+}
+
+// Log filter comes from EYE_LOG, then RUST_LOG, defaulting to info for this crate and warnings elsewhere
+fn init_logging() {
+    use tracing_subscriber::EnvFilter;
+
+    let filter = env::var("EYE_LOG")
+        .ok()
+        .and_then(|directives| EnvFilter::try_new(directives).ok())
+        .or_else(|| EnvFilter::try_from_default_env().ok())
+        .unwrap_or_else(|| EnvFilter::new("warn,zen_lib=info"));
+    let _ = tracing_subscriber::fmt().with_env_filter(filter).try_init();
+}
+
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
+pub fn run() {
+    // Load .env file variables into environment
+    dotenvy::dotenv().expect("Failed to load .env file. Please ensure it exists in src-tauri/");
+    init_logging();
+    let config = AppConfig::from_env();
 
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
@@ -821,24 +5091,146 @@ pub fn run() {
         .plugin(tauri_plugin_screenshots::init())
         .plugin(tauri_plugin_macos_permissions::init())
         .plugin(tauri_plugin_fs::init())
+        .plugin(
+            tauri_plugin_global_shortcut::Builder::new()
+                .with_handler(|app, _shortcut, event| {
+                    if event.state() == tauri_plugin_global_shortcut::ShortcutState::Pressed {
+                        handle_capture_shortcut(app);
+                    }
+                })
+                .build()
+        )
+        .manage(ChatStreams::default())
+        .manage(UploadCancellations::default())
+        .manage(UploadLimiter::new(config.max_concurrent_uploads))
+        .manage(ActiveDatabase::default())
+        .manage(PopupAutoClose::default())
+        .manage(config)
+        // Let the frontend auto-hide windows on blur (the payload is the window label)
+        // and restyle the frameless windows when the OS theme flips
+        .on_window_event(|window, event| match event {
+            tauri::WindowEvent::Focused(focused) => {
+                let name = if *focused { "window_focused" } else { "window_blurred" };
+                let _ = window.emit(name, window.label());
+            }
+            tauri::WindowEvent::ThemeChanged(theme) => {
+                debug!("System theme changed to {}", theme_name(*theme));
+                let _ = window.emit("theme_changed", theme_name(*theme));
+            }
+            _ => {}
+        })
         .plugin(
             tauri_plugin_sql::Builder::default()
-                .add_migrations("sqlite:notes.db", migrations)
+                .add_migrations(NOTES_DB_URL, notes_migrations())
                 .build()
         )
         .invoke_handler(tauri::generate_handler![
-            greet,
+            app_info,
+            get_system_theme,
             open_popup_window,
             close_popup_window,
+            enable_popup_auto_close,
+            disable_popup_auto_close,
+            toggle_popup_window,
+            set_popup_always_on_top,
+            set_popup_vibrancy,
+            set_window_opacity,
             chat,
+            chat_stream,
             upload_image_to_r2,
+            upload_bytes_to_r2,
+            cancel_upload,
+            cancel_all,
+            presign_r2_object,
+            r2_configured,
+            delete_r2_object,
+            test_r2_connection,
+            download_r2_object,
+            head_r2_object,
+            list_r2_objects,
+            save_chat_message,
+            create_conversation,
+            list_conversations,
+            set_conversation_system_prompt,
+            export_conversation_markdown,
+            create_note,
+            search_notes,
+            update_note,
+            list_captures,
+            switch_database,
+            delete_note,
+            clear_notes,
+            reset_database,
             chat_mastra,
+            cancel_chat_mastra,
+            list_mastra_agents,
             open_drag_window,
             close_drag_window,
-            capture_region_and_upload
+            toggle_drag_window,
+            set_drag_window_always_on_top,
+            grow_drag_window,
+            shrink_drag_window,
+            open_capture_frame_window,
+            close_capture_frame_window,
+            move_drag_window,
+            set_drag_window_size,
+            capture_region_and_upload,
+            capture_region_after_delay,
+            upload_clipboard_image,
+            capture_rect_and_upload,
+            capture_active_window_and_upload,
+            capture_window_by_title,
+            capture_scrolling_region,
+            capture_gif_and_upload,
+            capture_monitor_and_upload,
+            list_monitors,
+            capture_region_to_png,
+            capture_region_and_ocr,
+            capture_region_preview,
+            set_capture_shortcut,
+            copy_to_clipboard,
+            open_url,
+            check_screen_capture_permission,
+            request_screen_capture_permission,
+            cleanup_capture_cache
         ])
         // Add setup to ensure AppHandle is available for chat_mastra
         .setup(|app| {
+            // Surface missing R2 configuration now rather than deep inside an upload
+            let config = app.state::<AppConfig>();
+            let missing = config.r2.missing_env_vars();
+            let r2_client = if missing.is_empty() {
+                // Build the S3 client once so uploads don't pay for config loading each time
+                match tauri::async_runtime::block_on(build_r2_client(&config.r2, &config.proxy)) {
+                    Ok(client) => Some(client),
+                    Err(e) => {
+                        warn!("Failed to initialize R2 client: {:#}", e);
+                        None
+                    }
+                }
+            } else {
+                if !config.local_storage {
+                    warn!("R2 uploads are disabled, missing environment variables: {}", missing.join(", "));
+                }
+                None
+            };
+            app.manage(R2State(r2_client));
+
+            // Bind the region capture shortcut; a conflict shouldn't stop the app from starting
+            {
+                use tauri_plugin_global_shortcut::GlobalShortcutExt;
+                let shortcut = app.state::<AppConfig>().capture_shortcut.clone();
+                if let Err(e) = app.global_shortcut().register(shortcut.as_str()) {
+                    warn!("Failed to register capture shortcut {}: {}", shortcut, e);
+                }
+                app.manage(CaptureShortcut(Mutex::new(shortcut)));
+            }
+
+            // Clear out captures left behind by earlier runs
+            if let Err(e) = cleanup_capture_cache(app.handle().clone(), CAPTURE_CACHE_MAX_AGE_SECS) {
+                warn!("Failed to clean capture cache: {}", e);
+            }
+
             // Allow access to the screenshots directory
             #[cfg(target_os = "macos")]
             {
@@ -848,6 +5240,283 @@ pub fn run() {
             }
             Ok(())
         })
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            if let tauri::RunEvent::ExitRequested { api, .. } = event {
+                // The second request comes from our own exit() below
+                if SHUTTING_DOWN.swap(true, Ordering::SeqCst) {
+                    return;
+                }
+                let in_flight = cancel_in_flight_work(app);
+                if in_flight > 0 {
+                    info!("Cancelling {} in-flight stream(s)/upload(s) before exit", in_flight);
+                    api.prevent_exit();
+                    let app = app.clone();
+                    tauri::async_runtime::spawn(async move {
+                        wait_for_in_flight_work(&app, SHUTDOWN_GRACE_PERIOD).await;
+                        app.exit(0);
+                    });
+                }
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    // Serves `/hop/<n>`: n > 0 redirects to `/hop/<n - 1>` (308 on even hops, 307 on odd),
+    // and `/hop/0` echoes the request body back as JSON
+    async fn spawn_redirect_server() -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(handle_redirect_request(socket));
+            }
+        });
+        addr
+    }
+
+    async fn handle_redirect_request(socket: tokio::net::TcpStream) {
+        let mut reader = BufReader::new(socket);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).await.unwrap();
+        let path = request_line.split_whitespace().nth(1).unwrap_or("/").to_string();
+
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await.unwrap() == 0 || line == "\r\n" {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).await.unwrap();
+
+        let (head, body) = match path.strip_prefix("/hop/").and_then(|n| n.parse::<u32>().ok()) {
+            Some(0) => (
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                ),
+                body,
+            ),
+            Some(n) => {
+                let status = if n % 2 == 0 { "308 Permanent Redirect" } else { "307 Temporary Redirect" };
+                (
+                    format!(
+                        "HTTP/1.1 {}\r\nLocation: /hop/{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        status,
+                        n - 1
+                    ),
+                    Vec::new(),
+                )
+            }
+            None => ("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(), Vec::new()),
+        };
+        let mut socket = reader.into_inner();
+        socket.write_all(head.as_bytes()).await.unwrap();
+        socket.write_all(&body).await.unwrap();
+        socket.shutdown().await.unwrap();
+    }
+
+    fn test_mastra_client() -> reqwest::Client {
+        mastra_http_client(&ProxySettings::default(), Duration::from_secs(5), Some(Duration::from_secs(5))).unwrap()
+    }
+
+    #[tokio::test]
+    async fn mastra_client_resends_json_body_across_307_and_308() {
+        let addr = spawn_redirect_server().await;
+        let body = serde_json::json!({ "messages": [{ "role": "user", "content": "hello" }] });
+
+        let res = test_mastra_client()
+            .post(format!("http://{}/hop/2", addr))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(res.url().path(), "/hop/0");
+        assert_eq!(res.json::<serde_json::Value>().await.unwrap(), body);
+    }
+
+    #[tokio::test]
+    async fn mastra_client_caps_redirect_hops() {
+        let addr = spawn_redirect_server().await;
+        let client = test_mastra_client();
+        let body = serde_json::json!({ "messages": [] });
+
+        let res = client
+            .post(format!("http://{}/hop/{}", addr, MASTRA_MAX_REDIRECTS))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+
+        let err = client
+            .post(format!("http://{}/hop/{}", addr, MASTRA_MAX_REDIRECTS + 1))
+            .json(&body)
+            .send()
+            .await
+            .unwrap_err();
+        assert!(err.is_redirect(), "expected a redirect error, got {}", err);
+    }
+
+    #[test]
+    fn detects_mastra_line_framing() {
+        assert_eq!(detect_stream_framing("0:\"x\""), Some(true));
+        assert_eq!(detect_stream_framing("f:{\"messageId\":\"1\"}"), Some(true));
+    }
+
+    #[test]
+    fn detects_sse_framing() {
+        assert_eq!(detect_stream_framing("data: {\"text\":\"hi\"}"), Some(true));
+    }
+
+    #[test]
+    fn waits_for_more_input_on_ambiguous_prefixes() {
+        assert_eq!(detect_stream_framing(""), None);
+        assert_eq!(detect_stream_framing("da"), None);
+        assert_eq!(detect_stream_framing("0"), None);
+        assert_eq!(detect_stream_framing("0:"), None);
+    }
+
+    #[test]
+    fn plain_text_is_unframed() {
+        assert_eq!(detect_stream_framing("Hello there"), Some(false));
+    }
+
+    #[test]
+    fn text_that_looks_like_a_prefix_is_unframed() {
+        assert_eq!(detect_stream_framing("I: hello"), Some(false));
+        assert_eq!(detect_stream_framing("a: hello"), Some(false));
+    }
+
+    #[test]
+    fn framing_ignores_leading_whitespace() {
+        assert_eq!(detect_stream_framing("\n  0:\"x\""), Some(true));
+        assert_eq!(detect_stream_framing("  data: {}"), Some(true));
+        assert_eq!(detect_stream_framing("\n\n"), None);
+    }
+
+    #[test]
+    fn framing_accepts_every_json_token_start() {
+        for line in ["0:\"x\"", "2:[1]", "8:{}", "0:-1", "0:42", "0:true", "0:false", "0:null"] {
+            assert_eq!(detect_stream_framing(line), Some(true), "{}", line);
+        }
+    }
+
+    #[test]
+    fn framing_rejects_near_misses() {
+        assert_eq!(detect_stream_framing("date: today"), Some(false));
+        assert_eq!(detect_stream_framing("A:{}"), Some(false));
+        assert_eq!(detect_stream_framing("ab"), Some(false));
+        assert_eq!(detect_stream_framing("data"), None);
+    }
+
+    #[test]
+    fn drain_utf8_keeps_a_split_character_for_the_next_chunk() {
+        let mut bytes = "hé".as_bytes().to_vec();
+        let last = bytes.pop().unwrap();
+        assert_eq!(drain_utf8(&mut bytes), "h");
+        assert_eq!(bytes.len(), 1);
+
+        bytes.push(last);
+        assert_eq!(drain_utf8(&mut bytes), "é");
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn drain_utf8_reassembles_a_four_byte_character_across_chunks() {
+        let emoji = "🦀".as_bytes();
+        let mut bytes = Vec::new();
+        let mut decoded = String::new();
+        for byte in emoji {
+            bytes.push(*byte);
+            decoded.push_str(&drain_utf8(&mut bytes));
+        }
+        assert_eq!(decoded, "🦀");
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn drain_utf8_replaces_invalid_bytes() {
+        let mut bytes = vec![b'a', 0xFF, b'b'];
+        assert_eq!(drain_utf8(&mut bytes), "a\u{FFFD}b");
+        assert!(bytes.is_empty());
+    }
+
+    // Chat helpers accept an optional window; tests run without one
+    fn no_window() -> Option<&'static tauri::WebviewWindow<tauri::Wry>> {
+        None
+    }
+
+    #[test]
+    fn coalescer_without_events_only_accumulates() {
+        let mut coalescer = ChunkCoalescer::new(no_window(), false, None);
+        coalescer.push("Hello, ").unwrap();
+        coalescer.push("world").unwrap();
+        assert!(coalescer.pending.is_empty());
+        coalescer.flush().unwrap();
+        assert_eq!(coalescer.into_text(), "Hello, world");
+    }
+
+    #[test]
+    fn coalescer_holds_small_chunks_until_flushed() {
+        let mut coalescer = ChunkCoalescer::new(no_window(), true, Some("conversation".to_string()));
+        coalescer.max_debounce = Duration::from_secs(60);
+        coalescer.push("Hel").unwrap();
+        coalescer.push("lo").unwrap();
+        assert_eq!(coalescer.pending, "Hello");
+
+        coalescer.flush().unwrap();
+        assert!(coalescer.pending.is_empty());
+        assert_eq!(coalescer.text(), "Hello");
+    }
+
+    #[test]
+    fn coalescer_emits_once_enough_text_is_pending() {
+        let mut coalescer = ChunkCoalescer::new(no_window(), true, None);
+        coalescer.max_debounce = Duration::from_secs(60);
+        coalescer.min_chunk_chars = 4;
+        coalescer.push("abc").unwrap();
+        assert_eq!(coalescer.pending, "abc");
+        coalescer.push("de").unwrap();
+        assert!(coalescer.pending.is_empty());
+        assert_eq!(coalescer.text(), "abcde");
+    }
+
+    #[test]
+    fn stream_errors_drop_repeats_of_the_last_message() {
+        let mut errors = StreamErrorDebouncer::default();
+        errors.push(no_window(), "boom").unwrap();
+        assert!(errors.pending.is_empty());
+        assert!(errors.last_emit.is_some());
+
+        errors.push(no_window(), "boom").unwrap();
+        assert!(errors.pending.is_empty());
+    }
+
+    #[test]
+    fn stream_errors_batch_a_burst_until_flushed() {
+        let mut errors = StreamErrorDebouncer::default();
+        errors.push(no_window(), "first").unwrap();
+        errors.push(no_window(), "second").unwrap();
+        errors.push(no_window(), "second").unwrap();
+        errors.push(no_window(), "third").unwrap();
+        assert_eq!(errors.pending, vec!["second".to_string(), "third".to_string()]);
+
+        errors.flush(no_window()).unwrap();
+        assert!(errors.pending.is_empty());
+    }
 }
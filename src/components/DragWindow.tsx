@@ -255,6 +255,9 @@ export default function DragWindow() {
         prompt: userPromptContent,
         messagesHistory: historyForBackend,
         imageUrl: signedImageUrl, // Pass the pre-signed URL
+        agent: "weatherAgent",
+        requestId: uuidv4(),
+        stream: true,
       });
 
       // 4. Clear preview state AFTER successful invocation
@@ -279,7 +282,7 @@ export default function DragWindow() {
       let errorMessage = "Failed to send message.";
       if (typeof err === "string") {
         errorMessage = err;
-      } else if (err instanceof Error) {
+      } else if (err && typeof err.message === "string") {
         errorMessage = err.message;
       }
       setFetchError(errorMessage);
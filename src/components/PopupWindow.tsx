@@ -12,10 +12,6 @@ import {
   getScreenshotableMonitors,
   getMonitorScreenshot,
 } from "tauri-plugin-screenshots-api";
-import {
-  checkScreenRecordingPermission,
-  requestScreenRecordingPermission,
-} from "tauri-plugin-macos-permissions-api";
 import { readFile, BaseDirectory, writeFile } from "@tauri-apps/plugin-fs";
 import { openPath } from "@tauri-apps/plugin-opener";
 import { desktopDir } from "@tauri-apps/api/path";
@@ -162,6 +158,9 @@ export default function PopupWindow() {
         prompt: "",
         messagesHistory: [], // fresh conversation
         imageUrl,
+        agent: "weatherAgent",
+        requestId: uuidv4(),
+        stream: true,
       });
 
       greetingSentRef.current = true; // lock
@@ -170,7 +169,7 @@ export default function PopupWindow() {
       let errorMessage = "Failed to start greeting chat.";
       if (typeof err === "string") {
         errorMessage = err;
-      } else if (err instanceof Error) {
+      } else if (err && typeof err.message === "string") {
         errorMessage = err.message;
       }
       setFetchError(errorMessage);
@@ -305,6 +304,9 @@ export default function PopupWindow() {
         prompt: userPromptContent,
         messagesHistory: historyForBackend,
         imageUrl: signedImageUrl, // Pass the pre-signed URL from state (or null)
+        agent: "weatherAgent",
+        requestId: uuidv4(),
+        stream: true,
       });
 
       // 4. Clear preview state AFTER successful invocation
@@ -329,7 +331,7 @@ export default function PopupWindow() {
       let errorMessage = "Failed to send message.";
       if (typeof err === "string") {
         errorMessage = err;
-      } else if (err instanceof Error) {
+      } else if (err && typeof err.message === "string") {
         errorMessage = err.message;
       }
       setFetchError(errorMessage);
@@ -409,9 +411,9 @@ export default function PopupWindow() {
 
     try {
       // 1. Permission Check (same as before)
-      const hasPerm = await checkScreenRecordingPermission();
-      if (!hasPerm) {
-        const granted = await requestScreenRecordingPermission();
+      const permission = await invoke<string>("check_screen_capture_permission");
+      if (permission !== "granted") {
+        const granted = await invoke<boolean>("request_screen_capture_permission");
         if (!granted) {
           showToast("Screen recording permission denied", "error");
           setFetchError("Screen recording permission denied");